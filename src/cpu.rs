@@ -1,5 +1,10 @@
-use std::io;
-use crate::{cpu, parse, util};
+use std::collections::BTreeMap;
+use crate::{cpu, device, parse, util};
+use crate::device::{Console, Device, KeyQueue, Keyboard, KeyboardStatus, MappedRegion, PackedConsole, Timer};
+use crate::error::{AsmErrorKind, CpuError, Fault};
+use crate::memory::{Access, MemoryMap, Permissions, ProtectionMap, Section};
+use crate::rng::Rng;
+use crate::state::{StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
 
 pub const CPU_MEMORY_SIZE: usize = 0x10000;
 pub const VRAM_START: u16 = 0;
@@ -9,10 +14,24 @@ pub const BUILTIN_START: u16 = STACK_START + (CPU_MEMORY_SIZE >> 4) as u16;
 pub const HEAP_META_START: u16 = BUILTIN_START + (CPU_MEMORY_SIZE >> 5) as u16;
 pub const HEAP_DATA_START: u16 = (CPU_MEMORY_SIZE >> 1) as u16;
 
+pub const TRAP_VECTOR_COUNT: u16 = 0x0010;
+pub const IRQ_LINE_COUNT: u16 = 0x0010;
+
+pub const CONSOLE_PORT: u16 = 0x0000;
+pub const TIMER_PORT: u16 = 0x0001;
+pub const CONSOLE_PACKED_PORT: u16 = 0x0002;
+pub const KEYBOARD_PORT: u16 = 0x0003;
+pub const KEYBOARD_STATUS_PORT: u16 = 0x0004;
+pub const TIMER_IRQ_LINE: u16 = 0x0000;
+pub const KEYBOARD_IRQ_LINE: u16 = 0x0001;
+
 pub const SUCCESS_ERROR_CODE: u16 = 0x0000;
 pub const STACK_OVERFLOW_ERROR_CODE: u16 = 0x0010;
 pub const HEAP_ALLOC_ERROR_CODE: u16 = 0x0011;
 pub const DIV_ZERO_ERROR_CODE: u16 = 0x0020;
+pub const INVALID_INSTR_ERROR_CODE: u16 = 0x0030;
+pub const MEM_ACCESS_ERROR_CODE: u16 = 0x0031;
+pub const ILLEGAL_OP_ERROR_CODE: u16 = 0x0032;
 
 pub const BUILTIN_SUBROUTINES: [u16; 0] = [];
 
@@ -22,50 +41,327 @@ pub struct Cpu {
     a_register: u16,
     b_register: u16,
     c_register: u16,
+    hi_register: u16,
     instr_register: u16,
     instr_counter: u16,
     stack_counter: u16,
+    stack_base: u16,
+    stack_limit: u16,
+    stack_high_water: u16,
+    trap_table: u16,
+    trap_counter: u16,
+    irq_table: u16,
+    irq_counter: u16,
+    irq_pending: u16,
+    irq_mask: u16,
     err_code: u8,
     loop_flag: bool,
     jumped_flag: bool,
     load_const_flag: bool,
+    trap_flag: bool,
+    interrupt_enable_flag: bool,
+    wait_irq_flag: bool,
+    wrap_flag: bool,
     result_zero_flag: bool,
     result_negative_flag: bool,
     result_overflow_flag: bool,
+    result_carry_flag: bool,
+    result_less_flag: bool,
+    fault: Option<Fault>,
+    protection: Option<ProtectionMap>,
+    memory_map: MemoryMap,
+    devices: BTreeMap<u16, Box<dyn Device>>,
+    regions: Vec<MappedRegion>,
+    keys: KeyQueue,
+    rng: Rng,
 }
 
 impl Cpu {
-    pub fn new(code: Vec<u16>, builtin: Vec<u16>) -> Result<Self, String> {
+    pub fn new(code: Vec<u16>, builtin: Vec<u16>, memory_map: MemoryMap, seed: u64) -> Result<Self, CpuError> {
         let mut mem = vec![0u16; CPU_MEMORY_SIZE];
 
-        if code.len() > (STACK_START - PROGRAM_START) as usize {
-            return Err(format!("Program size {} exceeds maximum size of {}", code.len(), STACK_START - PROGRAM_START));
+        let program_start = memory_map.start(Section::Program);
+        if code.len() > memory_map.size(Section::Program) {
+            return Err(CpuError::ProgramTooLarge { size: code.len(), max: memory_map.size(Section::Program) });
         }
-        (&mut mem[(PROGRAM_START as usize)..(PROGRAM_START as usize + code.len())]).copy_from_slice(code.as_slice());
+        mem[(program_start as usize)..(program_start as usize + code.len())].copy_from_slice(code.as_slice());
 
-        if builtin.len() > (HEAP_META_START - BUILTIN_START) as usize {
-            return Err(format!("Built-in program size {} exceeds maximum size of {}", builtin.len(), HEAP_META_START - BUILTIN_START));
+        let builtin_start = memory_map.start(Section::Builtin);
+        if builtin.len() > (memory_map.irq_table() - builtin_start) as usize {
+            return Err(CpuError::BuiltinTooLarge { size: builtin.len(), max: (memory_map.irq_table() - builtin_start) as usize });
         }
-        (&mut mem[(BUILTIN_START as usize)..(BUILTIN_START as usize + builtin.len())]).copy_from_slice(&builtin);
+        mem[(builtin_start as usize)..(builtin_start as usize + builtin.len())].copy_from_slice(&builtin);
 
-        let first_instr = mem[PROGRAM_START as usize];
-        Ok(Cpu {
+        let first_instr = mem[program_start as usize];
+        let mut cpu = Cpu {
             cycle: 0,
             memory: mem,
             a_register: 0x0000,
             b_register: 0x0000,
             c_register: 0x0000,
+            hi_register: 0x0000,
             instr_register: first_instr,
-            instr_counter: PROGRAM_START,
-            stack_counter: STACK_START,
+            instr_counter: program_start,
+            stack_counter: memory_map.start(Section::Stack),
+            stack_base: memory_map.start(Section::Stack),
+            stack_limit: builtin_start,
+            stack_high_water: memory_map.start(Section::Stack),
+            trap_table: memory_map.trap_table(),
+            trap_counter: 0x0000,
+            irq_table: memory_map.irq_table(),
+            irq_counter: 0x0000,
+            irq_pending: 0x0000,
+            irq_mask: 0xffff,
             err_code: 0x00,
             loop_flag: true,
             jumped_flag: false,
             load_const_flag: false,
+            trap_flag: false,
+            interrupt_enable_flag: false,
+            wait_irq_flag: false,
+            wrap_flag: false,
             result_zero_flag: false,
             result_negative_flag: false,
             result_overflow_flag: false,
-        })
+            result_carry_flag: false,
+            result_less_flag: false,
+            fault: None,
+            protection: None,
+            memory_map,
+            devices: BTreeMap::new(),
+            regions: Vec::new(),
+            keys: KeyQueue::default(),
+            rng: Rng::new(seed),
+        };
+        let keys = cpu.keys.clone();
+        cpu.attach_device(CONSOLE_PORT, Box::new(Console::new(keys.clone())));
+        cpu.attach_device(TIMER_PORT, Box::new(Timer::new(TIMER_IRQ_LINE)));
+        cpu.attach_device(CONSOLE_PACKED_PORT, Box::new(PackedConsole));
+        cpu.attach_device(KEYBOARD_PORT, Box::new(Keyboard::new(KEYBOARD_IRQ_LINE, keys.clone())));
+        cpu.attach_device(KEYBOARD_STATUS_PORT, Box::new(KeyboardStatus::new(keys)));
+        Ok(cpu)
+    }
+
+    pub fn attach_device(&mut self, port: u16, device: Box<dyn Device>) -> Option<Box<dyn Device>> {
+        self.devices.insert(port, device)
+    }
+
+    pub fn detach_device(&mut self, port: u16) -> Option<Box<dyn Device>> {
+        self.devices.remove(&port)
+    }
+
+    pub fn get_devices(&self) -> impl Iterator<Item = (u16, &dyn Device)> {
+        self.devices.iter().map(|(port, device)| (*port, device.as_ref()))
+    }
+
+    pub fn map_device(&mut self, start: u16, len: u16, device: Box<dyn Device>) -> Result<(), CpuError> {
+        self.check_region(start, len)?;
+        self.regions.push(MappedRegion { start, len, device });
+        Ok(())
+    }
+
+    pub fn check_region(&self, start: u16, len: u16) -> Result<(), CpuError> {
+        let end = start as usize + len as usize;
+        if len == 0 || end > CPU_MEMORY_SIZE
+            || self.regions.iter().any(|other| (start as usize) < other.end() && (other.start as usize) < end) {
+            return Err(CpuError::RegionConflict { start, len });
+        }
+        Ok(())
+    }
+
+    pub fn get_regions(&self) -> impl Iterator<Item = &MappedRegion> {
+        self.regions.iter()
+    }
+
+    pub fn get_rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    // a fault isn't saved, a cpu saved at a fault retries the faulting instruction once loaded and resumed
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::default();
+        state.raw(STATE_MAGIC);
+        state.u16(STATE_VERSION);
+        state.u64(self.cycle);
+        for register in self.saved_registers() {
+            state.u16(register);
+        }
+        state.u8(self.err_code);
+        for flag in self.saved_flags() {
+            state.bool(flag);
+        }
+        state.bool(self.protection.is_some());
+        if let Some(protection) = &self.protection {
+            for section in Section::ALL {
+                state.u8(protection.get(section).bits());
+            }
+        }
+        for section in Section::ALL {
+            state.u16(self.memory_map.start(section));
+        }
+        state.u64(self.rng.seed());
+        state.u64(self.rng.state());
+        let keys = self.keys.queued();
+        state.u32(keys.len() as u32);
+        for key in keys {
+            state.u16(key);
+        }
+        state.bool(self.keys.is_closed());
+        state.u16(self.devices.len() as u16);
+        for (port, device) in &self.devices {
+            state.u16(*port);
+            state.str(device.name());
+            let mut device_state = StateWriter::default();
+            device.save_state(&mut device_state);
+            state.bytes(&device_state.into_bytes());
+        }
+        state.u16(self.regions.len() as u16);
+        for region in &self.regions {
+            state.u16(region.start);
+            state.u16(region.len);
+            state.str(region.device.name());
+            let mut device_state = StateWriter::default();
+            region.device.save_state(&mut device_state);
+            state.bytes(&device_state.into_bytes());
+        }
+        for word in &self.memory {
+            state.u16(*word);
+        }
+        state.into_bytes()
+    }
+
+    // the cpu is left untouched if the state fails to load
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), CpuError> {
+        let mut state = StateReader::new(bytes);
+        if state.raw(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err(CpuError::InvalidState("not an ember save state".into()));
+        }
+        let version = state.u16()?;
+        if version != STATE_VERSION {
+            return Err(CpuError::InvalidState(format!("unsupported version {}, expected {}", version, STATE_VERSION)));
+        }
+        let cycle = state.u64()?;
+        let mut registers = [0u16; 16];
+        for register in &mut registers {
+            *register = state.u16()?;
+        }
+        let err_code = state.u8()?;
+        let mut flags = [false; 12];
+        for flag in &mut flags {
+            *flag = state.bool()?;
+        }
+        let protection = if state.bool()? {
+            let mut protection = ProtectionMap::default();
+            for section in Section::ALL {
+                protection.set(section, Permissions::from_bits(state.u8()?));
+            }
+            Some(protection)
+        } else {
+            None
+        };
+        let mut starts = [0u16; 6];
+        for start in &mut starts {
+            *start = state.u16()?;
+        }
+        let memory_map = MemoryMap::from_starts(starts).map_err(CpuError::InvalidState)?;
+        let rng = Rng::restore(state.u64()?, state.u64()?);
+        let key_count = state.u32()?;
+        let keys = (0..key_count).map(|_| state.u16()).collect::<Result<Vec<_>, _>>()?;
+        let keys_closed = state.bool()?;
+
+        let mut device_states = BTreeMap::new();
+        for _ in 0..state.u16()? {
+            let port = state.u16()?;
+            let name = state.str()?;
+            match self.devices.get(&port) {
+                Some(device) if device.name() == name => { device_states.insert(port, state.bytes()?); }
+                _ => return Err(CpuError::InvalidState(format!("saved device {} isn't attached to port {:#06x}", name, port))),
+            }
+        }
+        let mut region_states = BTreeMap::new();
+        for _ in 0..state.u16()? {
+            let (start, len, name) = (state.u16()?, state.u16()?, state.str()?);
+            match self.regions.iter().position(|region| region.start == start && region.len == len && region.device.name() == name) {
+                Some(i) => { region_states.insert(i, state.bytes()?); }
+                None => return Err(CpuError::InvalidState(format!("saved device {} isn't mapped to {:#06x}..{:#06x}", name, start, start as usize + len as usize))),
+            }
+        }
+        let memory = state.raw(CPU_MEMORY_SIZE * 2)?.chunks_exact(2).map(|word| ((word[0] as u16) << 8) | word[1] as u16).collect();
+        state.finish()?;
+        self.load_device_states(&device_states, &region_states)?;
+
+        self.cycle = cycle;
+        // same order as `saved_registers` and `saved_flags`
+        let [a, b, c, hi, instr, ictr, sctr, sbase, slim, shigh, ttab, tctr, itab, ictr_irq, pending, mask] = registers;
+        (self.a_register, self.b_register, self.c_register, self.hi_register) = (a, b, c, hi);
+        (self.instr_register, self.instr_counter) = (instr, ictr);
+        (self.stack_counter, self.stack_base, self.stack_limit, self.stack_high_water) = (sctr, sbase, slim, shigh);
+        (self.trap_table, self.trap_counter, self.irq_table, self.irq_counter) = (ttab, tctr, itab, ictr_irq);
+        (self.irq_pending, self.irq_mask) = (pending, mask);
+        self.err_code = err_code;
+        let [looping, jumped, load_const, trap, interrupt_enable, wait_irq, wrap, zero, negative, overflow, carry, less] = flags;
+        (self.loop_flag, self.jumped_flag, self.load_const_flag, self.trap_flag) = (looping, jumped, load_const, trap);
+        (self.interrupt_enable_flag, self.wait_irq_flag, self.wrap_flag) = (interrupt_enable, wait_irq, wrap);
+        (self.result_zero_flag, self.result_negative_flag, self.result_overflow_flag) = (zero, negative, overflow);
+        (self.result_carry_flag, self.result_less_flag) = (carry, less);
+        self.fault = None;
+        self.protection = protection;
+        self.memory_map = memory_map;
+        self.rng = rng;
+        self.keys.replace(keys, keys_closed);
+        self.memory = memory;
+        Ok(())
+    }
+
+    // every device is rolled back to its current state if any of them fails to load
+    fn load_device_states(&mut self, ports: &BTreeMap<u16, &[u8]>, regions: &BTreeMap<usize, &[u8]>) -> Result<(), CpuError> {
+        let mut targets = self.devices.iter_mut()
+            .filter_map(|(port, device)| ports.get(port).map(|data| (device, *data)))
+            .chain(self.regions.iter_mut().enumerate().filter_map(|(i, region)| regions.get(&i).map(|data| (&mut region.device, *data))))
+            .collect::<Vec<_>>();
+        let backups = targets.iter().map(|(device, _)| {
+            let mut backup = StateWriter::default();
+            device.save_state(&mut backup);
+            backup.into_bytes()
+        }).collect::<Vec<_>>();
+        let loaded = targets.iter_mut().try_for_each(|(device, data)| {
+            let mut device_state = StateReader::new(data);
+            device.load_state(&mut device_state)?;
+            device_state.finish()
+        });
+        if loaded.is_err() {
+            for ((device, _), backup) in targets.iter_mut().zip(&backups) {
+                let _ = device.load_state(&mut StateReader::new(backup));
+            }
+        }
+        loaded
+    }
+
+    fn saved_registers(&self) -> [u16; 16] {
+        [
+            self.a_register, self.b_register, self.c_register, self.hi_register,
+            self.instr_register, self.instr_counter,
+            self.stack_counter, self.stack_base, self.stack_limit, self.stack_high_water,
+            self.trap_table, self.trap_counter, self.irq_table, self.irq_counter,
+            self.irq_pending, self.irq_mask,
+        ]
+    }
+
+    fn saved_flags(&self) -> [bool; 12] {
+        [
+            self.loop_flag, self.jumped_flag, self.load_const_flag, self.trap_flag,
+            self.interrupt_enable_flag, self.wait_irq_flag, self.wrap_flag,
+            self.result_zero_flag, self.result_negative_flag, self.result_overflow_flag,
+            self.result_carry_flag, self.result_less_flag,
+        ]
+    }
+
+    pub fn get_keys(&self) -> KeyQueue {
+        self.keys.clone()
     }
 
     pub fn get_cycle(&self) -> u64 {
@@ -96,20 +392,30 @@ impl Cpu {
         self.c_register = val
     }
 
-    pub fn get_address(&self, address: u16) -> Result<u16, String> {
+    pub fn get_address(&mut self, address: u16) -> Result<u16, CpuError> {
+        if let Some(region) = self.regions.iter_mut().find(|region| region.contains(address)) {
+            return region.device.load(address - region.start);
+        }
         if (address as usize) < self.memory.len() {
             Ok(self.memory[address as usize])
         } else {
-            Err(format!("Address {} is out of bounds of memory of size {}", address, self.memory.len()))
+            Err(CpuError::OutOfBounds { address: address as usize, size: self.memory.len() })
         }
     }
 
-    pub fn set_address(&mut self, address: u16, value: u16) -> Result<(), String> {
+    pub fn set_address(&mut self, address: u16, value: u16) -> Result<(), CpuError> {
+        if let Some(region) = self.regions.iter_mut().find(|region| region.contains(address)) {
+            return region.device.store(address - region.start, value);
+        }
         if (address as usize) < self.memory.len() {
             self.memory[address as usize] = value;
+            // patching the current instruction takes effect without waiting for the next fetch
+            if address == self.instr_counter {
+                self.instr_register = value;
+            }
             Ok(())
         } else {
-            Err(format!("Address {} is out of bounds of memory of size {}", address, self.memory.len()))
+            Err(CpuError::OutOfBounds { address: address as usize, size: self.memory.len() })
         }
     }
 
@@ -117,27 +423,127 @@ impl Cpu {
         self.instr_counter
     }
 
+    pub fn set_instr_counter(&mut self, val: u16) {
+        self.instr_counter = val;
+        self.instr_register = self.memory[val as usize];
+    }
+
     pub fn get_const_flag(&self) -> bool {
         self.load_const_flag
     }
 
+    pub fn get_fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    pub fn get_memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn get_vram(&self) -> &[u16] {
+        &self.memory[(self.memory_map.start(Section::Vram) as usize)..self.memory_map.end(Section::Vram)]
+    }
+
+    pub fn get_protection(&self) -> Option<&ProtectionMap> {
+        self.protection.as_ref()
+    }
+
+    pub fn set_protection(&mut self, protection: Option<ProtectionMap>) {
+        self.protection = protection;
+    }
+
+    fn check_access(&self, address: u16, access: Access) -> Result<(), CpuError> {
+        if (address as usize) >= self.memory.len() {
+            return Err(CpuError::OutOfBounds { address: address as usize, size: self.memory.len() });
+        }
+        // instructions are fetched from memory, so code can't run from a device
+        if access == Access::Execute && self.regions.iter().any(|region| region.contains(address)) {
+            return Err(CpuError::AccessViolation { address, section: self.memory_map.section(address), access });
+        }
+        match &self.protection {
+            Some(protection) if !protection.allows(self.memory_map.section(address), access) => {
+                Err(CpuError::AccessViolation { address, section: self.memory_map.section(address), access })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read_memory(&mut self, address: u16) -> Result<u16, CpuError> {
+        self.check_access(address, Access::Read)?;
+        if let Some(region) = self.regions.iter_mut().find(|region| region.contains(address)) {
+            return region.device.load(address - region.start);
+        }
+        Ok(self.memory[address as usize])
+    }
+
+    fn write_memory(&mut self, address: u16, value: u16) -> Result<(), CpuError> {
+        self.check_access(address, Access::Write)?;
+        if let Some(region) = self.regions.iter_mut().find(|region| region.contains(address)) {
+            return region.device.store(address - region.start, value);
+        }
+        self.memory[address as usize] = value;
+        Ok(())
+    }
+
+    fn push_stack(&mut self, value: u16) -> Result<(), CpuError> {
+        if self.stack_counter >= self.stack_limit {
+            return Err(CpuError::StackOverflow { counter: self.stack_counter.wrapping_add(1), limit: self.stack_limit });
+        }
+        self.write_memory(self.stack_counter, value)?;
+        self.stack_counter += 1;
+        self.stack_high_water = self.stack_high_water.max(self.stack_counter);
+        Ok(())
+    }
+
+    fn stack_address(&self, n: u16) -> Result<u16, CpuError> {
+        let address = self.stack_counter.wrapping_sub(n).wrapping_sub(1);
+        if self.stack_counter <= self.stack_base || address < self.stack_base || address >= self.stack_counter {
+            return Err(CpuError::StackUnderflow { counter: address, base: self.stack_base });
+        }
+        Ok(address)
+    }
+
+    fn pop_stack(&mut self, n: u16) -> Result<u16, CpuError> {
+        let address = self.stack_address(n)?;
+        let value = self.read_memory(address)?;
+        for i in (address + 1)..self.stack_counter {
+            let moved = self.read_memory(i)?;
+            self.write_memory(i - 1, moved)?;
+        }
+        self.stack_counter -= 1;
+        Ok(value)
+    }
+
+    pub fn raise_irq(&mut self, line: u16) {
+        self.irq_pending |= 1 << (line & 0x000f);
+    }
+
     pub fn registers_info(&self) -> String {
         format!(
-            "| {:016x}: {:8} | {}{}{} | {}{}{} | {:02x} | {:04x} {:04x} {:04x} | I: {:04x} S: {:04x} |",
+            "| {:016x}: {:8} | {}{}{}{}{}{}{}{} | {}{}{}{}{} | {:02x} | {:04x} {:04x} {:04x} | I: {:04x} S: {:04x} H: {:04x} | P: {:04x} |",
             self.cycle,
             parse::parse(self.load_const_flag, self.instr_register).map_or("????".into(), |i| i.get_name()),
             if !self.loop_flag { "H" } else { " " },
             if self.jumped_flag { "J" } else { " " },
             if self.load_const_flag { "C" } else { " " },
+            if self.trap_flag { "T" } else { " " },
+            if self.fault.is_some() { "F" } else { " " },
+            if self.interrupt_enable_flag { "E" } else { " " },
+            if self.wait_irq_flag { "W" } else { " " },
+            if self.wrap_flag { "M" } else { " " },
             if self.result_zero_flag { "Z" } else { " " },
             if self.result_negative_flag { "N" } else { " " },
             if self.result_overflow_flag { "O" } else { " " },
+            if self.result_carry_flag { "Y" } else { " " },
+            if self.result_less_flag { "L" } else { " " },
             self.err_code,
             self.a_register,
             self.b_register,
             self.c_register,
             self.instr_counter,
             self.stack_counter,
+            self.stack_high_water,
+            self.irq_pending,
         )
         // format!(
         //     "mem size: {} words\n\
@@ -195,7 +601,6 @@ impl Cpu {
     pub fn partial_mem_dump(&self, section: u16) -> String {
         let mut info = String::new();
         let section_address = section;
-        let section = section;
 
         for i in (0..0x100).step_by(0x10) {
             info.push_str(&format!("0x{:04x} |", section_address + i));
@@ -213,7 +618,7 @@ impl Cpu {
         info
     }
 
-    pub fn run_instr(&mut self, instr: CpuInstr) -> Result<(), String> {
+    pub fn run_instr(&mut self, instr: CpuInstr) -> Result<(), CpuError> {
         if self.load_const_flag {
             self.a_register = instr.instr_code();
             self.load_const_flag = false;
@@ -227,28 +632,119 @@ impl Cpu {
         self.loop_flag
     }
 
-    pub fn cycle(&mut self) -> Result<(), String> {
-        if let Some(instr) = parse::parse(self.load_const_flag, self.instr_register) {
-            self.jumped_flag = false;
-            self.run_instr(instr)?;
-            if !self.jumped_flag { self.instr_counter += 1; }
+    pub fn resume(&mut self) {
+        self.fault = None;
+        self.instr_register = self.memory[self.instr_counter as usize];
+        self.loop_flag = true;
+    }
+
+    fn tick_devices(&mut self, cycles: u64) {
+        let lines = self.devices.values_mut()
+            .chain(self.regions.iter_mut().map(|region| &mut region.device))
+            .filter_map(|device| device.tick(cycles))
+            .collect::<Vec<_>>();
+        for line in lines {
+            self.raise_irq(line);
+        }
+    }
+
+    // lines without a handler stay pending, any pending unmasked line wakes the cpu from wfi
+    fn dispatch_irq(&mut self) {
+        let pending = self.irq_pending & self.irq_mask;
+        if pending == 0 {
+            return;
+        }
+        self.wait_irq_flag = false;
+        if !self.interrupt_enable_flag || self.load_const_flag || self.trap_flag {
+            return;
+        }
+        let handled = (0..16u16)
+            .filter(|line| pending & (1 << line) != 0)
+            .map(|line| (line, self.memory[self.irq_table.wrapping_add(line) as usize]))
+            .find(|(_, handler)| *handler != 0);
+        if let Some((line, handler)) = handled {
+            self.irq_pending &= !(1 << line);
+            self.irq_counter = self.instr_counter;
+            self.interrupt_enable_flag = false;
+            self.instr_counter = handler;
+            self.instr_register = self.memory[handler as usize];
+        }
+    }
+
+    pub fn cycle(&mut self) -> Result<(), CpuError> {
+        let start_cycle = self.cycle;
+        self.dispatch_irq();
+        if self.wait_irq_flag {
             self.cycle += 1;
-            self.instr_register = self.memory[self.instr_counter as usize];
-            Ok(())
-        } else {
-            Err(format!("Failed to parse instruction {}", self.instr_register))
+            self.tick_devices(1);
+            return Ok(());
         }
+
+        let result = self.check_access(self.instr_counter, Access::Execute).and_then(|_| {
+            match parse::parse(self.load_const_flag, self.instr_register) {
+                Some(instr) => {
+                    self.jumped_flag = false;
+                    self.run_instr(instr)
+                }
+                None => Err(CpuError::InvalidInstruction { word: self.instr_register, address: self.instr_counter }),
+            }
+        });
+        if let Err(err) = result {
+            let handled = match err.trap() {
+                Some(trap) => {
+                    self.err_code = trap.error_code() as u8;
+                    self.enter_trap(trap, self.instr_counter)
+                }
+                None => false,
+            };
+            if !handled {
+                // leave the instruction counter on the faulting instruction so it can be patched and retried
+                self.fault = Some(Fault { address: self.instr_counter, word: self.instr_register, error: err.clone() });
+                self.loop_flag = false;
+                self.tick_devices(self.cycle - start_cycle);
+                return Err(err);
+            }
+        }
+        if !self.jumped_flag { self.instr_counter += 1; }
+        self.cycle += 1;
+        self.instr_register = self.memory[self.instr_counter as usize];
+        // block instructions take more than one cycle
+        self.tick_devices(self.cycle - start_cycle);
+        Ok(())
     }
 
-    pub fn exec(&mut self, instr: CpuInstr) -> Result<(), String> {
+    // returns false if no handler is installed or the cpu is already inside a trap handler
+    fn enter_trap(&mut self, trap: Trap, return_address: u16) -> bool {
+        if self.trap_flag {
+            return false;
+        }
+        let handler = self.memory[self.trap_table.wrapping_add(trap.vector()) as usize];
+        if handler == 0 {
+            return false;
+        }
+        self.trap_counter = return_address;
+        self.trap_flag = true;
+        self.load_const_flag = false;
+        self.instr_counter = handler;
+        self.jumped_flag = true;
+        true
+    }
+
+    pub fn exec(&mut self, instr: CpuInstr) -> Result<(), CpuError> {
         match instr {
             CpuInstr::Wait => {}
             CpuInstr::Halt => {
                 self.loop_flag = false;
-                self.instr_counter = PROGRAM_START;
+                self.instr_counter = self.memory_map.start(Section::Program);
                 self.result_zero_flag = false;
                 self.result_negative_flag = false;
                 self.result_overflow_flag = false;
+                self.result_carry_flag = false;
+                self.result_less_flag = false;
+                self.wrap_flag = false;
+                self.trap_flag = false;
+                self.interrupt_enable_flag = false;
+                self.wait_irq_flag = false;
                 self.err_code = 0;
             }
             CpuInstr::Pause => {
@@ -259,6 +755,46 @@ impl Cpu {
             }
             CpuInstr::SetError => {
                 self.err_code = self.a_register as u8;
+                if self.err_code as u16 != SUCCESS_ERROR_CODE {
+                    self.enter_trap(Trap::Software, self.instr_counter.wrapping_add(1));
+                }
+            }
+            CpuInstr::GetError => {
+                self.a_register = self.err_code as u16;
+            }
+            CpuInstr::MoveToTrapTable => {
+                self.trap_table = self.a_register;
+            }
+            CpuInstr::TrapCounter => {
+                self.a_register = self.trap_counter;
+            }
+            CpuInstr::MoveToTrapCounter => {
+                self.trap_counter = self.a_register;
+            }
+            CpuInstr::ReturnFromTrap => {
+                self.instr_counter = self.trap_counter;
+                self.trap_flag = false;
+                self.jumped_flag = true;
+            }
+            CpuInstr::EnableInterrupts => {
+                self.interrupt_enable_flag = true;
+            }
+            CpuInstr::DisableInterrupts => {
+                self.interrupt_enable_flag = false;
+            }
+            CpuInstr::ReturnFromInterrupt => {
+                self.instr_counter = self.irq_counter;
+                self.interrupt_enable_flag = true;
+                self.jumped_flag = true;
+            }
+            CpuInstr::WaitForInterrupt => {
+                self.wait_irq_flag = true;
+            }
+            CpuInstr::MoveToInterruptMask => {
+                self.irq_mask = self.a_register;
+            }
+            CpuInstr::MoveToInterruptTable => {
+                self.irq_table = self.a_register;
             }
             CpuInstr::Move { from, to } => {
                 match (from, to) {
@@ -268,9 +804,7 @@ impl Cpu {
                     (Register::C, Register::A) => { self.a_register = self.c_register; }
                     (Register::B, Register::C) => { self.c_register = self.b_register; }
                     (Register::C, Register::B) => { self.b_register = self.c_register; }
-                    (Register::A, Register::A) => { return Err(String::from("Can't move a register to itself")); }
-                    (Register::B, Register::B) => { return Err(String::from("Can't move a register to itself")); }
-                    (Register::C, Register::C) => { return Err(String::from("Can't move a register to itself")); }
+                    (register, _) => { return Err(CpuError::IllegalMove { register }); }
                 }
             }
             CpuInstr::LoadConst => {
@@ -280,7 +814,7 @@ impl Cpu {
                 if self.load_const_flag {
                     self.a_register = val;
                 } else {
-                    return Err(String::from("Tried to load a constant without Load Constant Flag being set"));
+                    return Err(CpuError::ConstWithoutFlag);
                 }
                 self.load_const_flag = false;
             }
@@ -290,44 +824,96 @@ impl Cpu {
             CpuInstr::InstrCounter => {
                 self.a_register = self.instr_counter;
             }
+            CpuInstr::AddInstrCounter => {
+                self.a_register = self.instr_counter.wrapping_add(self.a_register);
+            }
             CpuInstr::StackCounter => {
                 self.a_register = self.stack_counter;
             }
             CpuInstr::MoveToStackCounter => {
+                if self.a_register > self.stack_limit {
+                    return Err(CpuError::StackOverflow { counter: self.a_register, limit: self.stack_limit });
+                }
+                if self.a_register < self.stack_base {
+                    return Err(CpuError::StackUnderflow { counter: self.a_register, base: self.stack_base });
+                }
                 self.stack_counter = self.a_register;
+                self.stack_high_water = self.stack_high_water.max(self.stack_counter);
+            }
+            CpuInstr::MoveToStackBase => {
+                self.stack_base = self.a_register;
+                self.stack_high_water = self.stack_base;
+            }
+            CpuInstr::MoveToStackLimit => {
+                self.stack_limit = self.a_register;
             }
             CpuInstr::Input => {
-                match self.a_register {
-                    0 => {
-                        let mut s = String::new();
-                        if let Ok(_) = io::stdin().read_line(&mut s) {
-                            if let Some(byte) = s.bytes().next() {
-                                self.b_register = byte as u16;
-                            }
-                        }
-                    }
-                    _ => {}
+                let port = self.a_register;
+                match self.devices.get_mut(&port) {
+                    Some(device) => self.b_register = device.read()?,
+                    None => return Err(CpuError::UnknownPort { port }),
                 }
             }
             CpuInstr::Output => {
-                match self.a_register {
-                    0 => {
-                        print!("{}", self.b_register as u8 as char);
-                    }
-                    _ => {}
+                let port = self.a_register;
+                match self.devices.get_mut(&port) {
+                    Some(device) => device.write(self.b_register)?,
+                    None => return Err(CpuError::UnknownPort { port }),
                 }
             }
             CpuInstr::MemRead => {
-                if self.b_register as usize > self.memory.len() {
-                    return Err(format!("Memory address {} out of range of memory size {}", self.b_register, self.memory.len()));
-                }
-                self.a_register = self.memory[self.b_register as usize];
+                self.a_register = self.read_memory(self.b_register)?;
             }
             CpuInstr::MemWrite => {
-                if self.a_register as usize > self.memory.len() {
-                    return Err(format!("Memory address {} out of range of memory size {}", self.b_register, self.memory.len()));
+                self.write_memory(self.a_register, self.b_register)?;
+            }
+            CpuInstr::MemCopy => {
+                let (dest, src, len) = (self.a_register, self.b_register, self.c_register);
+                // copies backwards when the destination overlaps the end of the source
+                let backwards = dest > src && dest - src < len;
+                for n in 0..len {
+                    let i = if backwards { len - 1 - n } else { n };
+                    // words copied before a fault are still charged
+                    let copied = self.read_memory(src.wrapping_add(i)).and_then(|value| self.write_memory(dest.wrapping_add(i), value));
+                    if copied.is_err() {
+                        self.cycle += n as u64;
+                    }
+                    copied?;
+                }
+                self.cycle += (len as u64).saturating_sub(1);
+            }
+            CpuInstr::MemSet => {
+                for i in 0..self.c_register {
+                    let written = self.write_memory(self.a_register.wrapping_add(i), self.b_register);
+                    if written.is_err() {
+                        self.cycle += i as u64;
+                    }
+                    written?;
                 }
-                self.memory[self.a_register as usize] = self.b_register;
+                self.cycle += (self.c_register as u64).saturating_sub(1);
+            }
+            CpuInstr::MemReadByte => {
+                let word = self.read_memory(self.c_register.wrapping_add(self.b_register >> 1))?;
+                self.a_register = if self.b_register & 1 == 0 { word >> 8 } else { word & 0x00ff };
+            }
+            CpuInstr::MemWriteByte => {
+                let address = self.c_register.wrapping_add(self.a_register >> 1);
+                let word = self.read_memory(address)?;
+                let byte = self.b_register & 0x00ff;
+                let word = if self.a_register & 1 == 0 { (word & 0x00ff) | byte << 8 } else { (word & 0xff00) | byte };
+                self.write_memory(address, word)?;
+            }
+            CpuInstr::MemReadIndexed => {
+                self.a_register = self.read_memory(self.b_register.wrapping_add(self.c_register))?;
+            }
+            CpuInstr::MemWriteIndexed => {
+                self.write_memory(self.a_register.wrapping_add(self.c_register), self.b_register)?;
+            }
+            CpuInstr::MemReadStack => {
+                self.a_register = self.read_memory(self.stack_counter.wrapping_sub(self.c_register))?;
+            }
+            CpuInstr::MemWriteStack => {
+                self.write_memory(self.stack_counter.wrapping_sub(self.c_register), self.b_register)?;
             }
             CpuInstr::Jump => {
                 self.instr_counter = self.a_register;
@@ -357,41 +943,281 @@ impl Cpu {
                     self.jumped_flag = true;
                 }
             }
+            CpuInstr::JumpIfCarry => {
+                if self.result_carry_flag {
+                    self.instr_counter = self.a_register;
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::JumpIfLess => {
+                if self.result_less_flag {
+                    self.instr_counter = self.a_register;
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::JumpIfGreaterOrEqual => {
+                if !self.result_less_flag {
+                    self.instr_counter = self.a_register;
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::JumpIfLessOrEqual => {
+                if self.result_less_flag || self.result_zero_flag {
+                    self.instr_counter = self.a_register;
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::JumpIfGreater => {
+                if !self.result_less_flag && !self.result_zero_flag {
+                    self.instr_counter = self.a_register;
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::JumpRelative(cond) => {
+                if self.condition(cond) {
+                    self.instr_counter = self.instr_counter.wrapping_add(self.a_register);
+                    self.jumped_flag = true;
+                }
+            }
+            CpuInstr::CallRelative => {
+                self.push_stack(self.instr_counter.wrapping_add(1))?;
+                self.instr_counter = self.instr_counter.wrapping_add(self.a_register);
+                self.jumped_flag = true;
+            }
+            CpuInstr::Call => {
+                self.push_stack(self.instr_counter.wrapping_add(1))?;
+                self.instr_counter = self.a_register;
+                self.jumped_flag = true;
+            }
+            CpuInstr::Return => {
+                self.instr_counter = self.pop_stack(0)?;
+                self.jumped_flag = true;
+            }
+            CpuInstr::ReturnFrom => {
+                self.instr_counter = self.pop_stack(self.b_register)?;
+                self.jumped_flag = true;
+            }
+            CpuInstr::Push => {
+                self.push_stack(self.a_register)?;
+            }
+            CpuInstr::Pop => {
+                self.a_register = self.pop_stack(0)?;
+            }
+            CpuInstr::Peek(n) => {
+                let address = self.stack_address(n as u16)?;
+                self.a_register = self.read_memory(address)?;
+            }
+            CpuInstr::Replace(n) => {
+                let address = self.stack_address(n as u16)?;
+                self.write_memory(address, self.a_register)?;
+            }
+            CpuInstr::Drop(n) => {
+                let counter = self.stack_counter.wrapping_sub(n as u16);
+                if counter < self.stack_base || counter > self.stack_counter {
+                    return Err(CpuError::StackUnderflow { counter, base: self.stack_base });
+                }
+                self.stack_counter = counter;
+            }
+            CpuInstr::WrapMode => {
+                self.wrap_flag = true;
+            }
+            CpuInstr::SaturateMode => {
+                self.wrap_flag = false;
+            }
+            CpuInstr::HighWord => {
+                self.a_register = self.hi_register;
+            }
+            CpuInstr::SetImmediate(val) => {
+                self.a_register = val as u16;
+            }
+            CpuInstr::JumpShort { cond, offset } => {
+                if self.condition(cond) {
+                    self.instr_counter = self.instr_counter.wrapping_add_signed(offset as i16);
+                    self.jumped_flag = true;
+                }
+            }
             CpuInstr::AluInstr { instr, pass } => {
-                let pass = pass || instr == AluInstr::NoOp;
-                let result = match instr {
-                    AluInstr::NoOp => self.a_register as i32,
-                    AluInstr::Increment => self.a_register as i32 + 1,
-                    AluInstr::Decrement => self.a_register as i32 - 1,
-                    AluInstr::Not => (!self.a_register) as i32,
-                    AluInstr::Or => (self.a_register | self.b_register) as i32,
-                    AluInstr::And => (self.a_register & self.b_register) as i32,
-                    AluInstr::Xor => (self.a_register ^ self.b_register) as i32,
-                    AluInstr::Add => (self.a_register as i32) + (self.b_register as i32),
-                    AluInstr::Subtract => (self.a_register as i32) - (self.b_register as i32),
-                    AluInstr::Multiply => (self.a_register as i32) * (self.b_register as i32),
-                    AluInstr::Random => rand::random::<u16>() as i32,
-                    AluInstr::ShiftLeftVar => (self.a_register as i32) << (self.b_register & 0x000f),
-                    AluInstr::ShiftLeft(shift) => (self.a_register as i32) << shift.value(),
-                    AluInstr::ShiftRightVar => (self.a_register as i32) >> (self.b_register & 0x000f),
-                    AluInstr::ShiftRight(shift) => (self.a_register as i32) >> shift.value(),
-                };
+                self.alu(instr, pass, self.b_register)?;
+            }
+            CpuInstr::AluImmediate { instr, pass, val } => {
+                self.alu(instr, pass, val as u16)?;
+            }
+        }
+        Ok(())
+    }
 
-                self.result_negative_flag = result < 0;
-                self.result_zero_flag = result == 0;
-                self.result_overflow_flag = result > 0xffff;
+    fn condition(&self, cond: JumpCond) -> bool {
+        match cond {
+            JumpCond::Always => true,
+            JumpCond::Zero => self.result_zero_flag,
+            JumpCond::Neg => self.result_negative_flag,
+            JumpCond::NegOrZero => self.result_negative_flag || self.result_zero_flag,
+            JumpCond::Overflow => self.result_overflow_flag,
+            JumpCond::Carry => self.result_carry_flag,
+            JumpCond::Less => self.result_less_flag,
+            JumpCond::GreaterOrEqual => !self.result_less_flag,
+            JumpCond::LessOrEqual => self.result_less_flag || self.result_zero_flag,
+            JumpCond::Greater => !self.result_less_flag && !self.result_zero_flag,
+        }
+    }
 
-                if !pass { self.a_register = if result < 0 { 0 } else { if result > 0xffff { 0xffff} else { result } } as u16; }
-            }
+    fn alu(&mut self, instr: AluInstr, pass: bool, operand: u16) -> Result<(), CpuError> {
+        let pass = pass || instr == AluInstr::NoOp || instr == AluInstr::SignedCompare;
+        let carry = self.result_carry_flag as i64;
+        let multiply = instr == AluInstr::Multiply || instr == AluInstr::SignedMultiply;
+        let compare = instr == AluInstr::SignedCompare;
+        let signed = compare || instr == AluInstr::SignedMultiply || instr == AluInstr::ShiftRightArith;
+        let result = match instr {
+            AluInstr::NoOp => self.a_register as i64,
+            AluInstr::Increment => self.a_register as i64 + 1,
+            AluInstr::Decrement => self.a_register as i64 - 1,
+            AluInstr::Not => (!self.a_register) as i64,
+            AluInstr::Or => (self.a_register | operand) as i64,
+            AluInstr::And => (self.a_register & operand) as i64,
+            AluInstr::Xor => (self.a_register ^ operand) as i64,
+            AluInstr::Add => (self.a_register as i64) + (operand as i64),
+            AluInstr::AddWithCarry => (self.a_register as i64) + (operand as i64) + carry,
+            AluInstr::Subtract => (self.a_register as i64) - (operand as i64),
+            AluInstr::SubtractWithBorrow => (self.a_register as i64) - (operand as i64) - carry,
+            AluInstr::Multiply => (self.a_register as i64) * (operand as i64),
+            AluInstr::SignedMultiply => (self.a_register as i16 as i64) * (operand as i16 as i64),
+            AluInstr::SignedCompare => (self.a_register as i16 as i64) - (operand as i16 as i64),
+            AluInstr::Divide | AluInstr::Modulo if operand == 0 => return Err(CpuError::DivideByZero),
+            AluInstr::Divide => (self.a_register / operand) as i64,
+            AluInstr::Modulo => (self.a_register % operand) as i64,
+            AluInstr::Random => self.rng.next_u16() as i64,
+            AluInstr::ShiftLeftVar => (self.a_register as i64) << (operand & 0x000f),
+            AluInstr::ShiftLeft(shift) => (self.a_register as i64) << shift.value(),
+            AluInstr::ShiftRightVar => (self.a_register as i64) >> (operand & 0x000f),
+            AluInstr::ShiftRightArith => ((self.a_register as i16) >> (operand & 0x000f)) as i64,
+            AluInstr::ShiftRight(shift) => (self.a_register as i64) >> shift.value(),
+        };
+        if multiply {
+            self.hi_register = (result >> 16) as u16;
         }
+
+        // in wrap mode results are stored modulo 0x10000 and the zero flag reflects the stored value,
+        // signed instructions always store the two's complement of their result
+        let wrapped = (result & 0xffff) as u16;
+        let modular = self.wrap_flag || signed;
+        let value = if modular { wrapped } else { result.clamp(0, 0xffff) as u16 };
+        let range = if signed { -0x8000..=0x7fff } else { 0..=0xffff };
+        self.result_negative_flag = result < 0;
+        self.result_zero_flag = if modular { wrapped == 0 } else { result == 0 };
+        self.result_overflow_flag = result > *range.end();
+        self.result_carry_flag = !range.contains(&result);
+        self.result_less_flag = if compare { result < 0 } else { (value as i16) < 0 };
+
+        if !pass { self.a_register = value; }
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trap {
+    Software,
+    InvalidInstruction,
+    MemoryAccess,
+    IllegalOperation,
+    StackOverflow,
+    DivideByZero,
+}
+
+impl Trap {
+    pub fn vector(&self) -> u16 {
+        match self {
+            Trap::Software => 0x0000,
+            Trap::InvalidInstruction => 0x0001,
+            Trap::MemoryAccess => 0x0002,
+            Trap::IllegalOperation => 0x0003,
+            Trap::StackOverflow => 0x0004,
+            Trap::DivideByZero => 0x0005,
+        }
+    }
+
+    pub fn error_code(&self) -> u16 {
+        match self {
+            Trap::Software => SUCCESS_ERROR_CODE,
+            Trap::InvalidInstruction => INVALID_INSTR_ERROR_CODE,
+            Trap::MemoryAccess => MEM_ACCESS_ERROR_CODE,
+            Trap::IllegalOperation => ILLEGAL_OP_ERROR_CODE,
+            Trap::StackOverflow => STACK_OVERFLOW_ERROR_CODE,
+            Trap::DivideByZero => DIV_ZERO_ERROR_CODE,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Trap> {
+        match name {
+            "software" => Some(Trap::Software),
+            "invalid_instr" => Some(Trap::InvalidInstruction),
+            "mem_access" => Some(Trap::MemoryAccess),
+            "illegal_op" => Some(Trap::IllegalOperation),
+            "stack_overflow" => Some(Trap::StackOverflow),
+            "div_zero" => Some(Trap::DivideByZero),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JumpCond {
+    Always,
+    Zero,
+    Neg,
+    NegOrZero,
+    Overflow,
+    Carry,
+    Less,
+    GreaterOrEqual,
+    LessOrEqual,
+    Greater,
+}
+
+impl JumpCond {
+    pub const ALL: [JumpCond; 10] = [
+        JumpCond::Always, JumpCond::Zero, JumpCond::Neg, JumpCond::NegOrZero, JumpCond::Overflow,
+        JumpCond::Carry, JumpCond::Less, JumpCond::GreaterOrEqual, JumpCond::LessOrEqual, JumpCond::Greater,
+    ];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            JumpCond::Always => "",
+            JumpCond::Zero => "z",
+            JumpCond::Neg => "n",
+            JumpCond::NegOrZero => "nz",
+            JumpCond::Overflow => "o",
+            JumpCond::Carry => "c",
+            JumpCond::Less => "lt",
+            JumpCond::GreaterOrEqual => "ge",
+            JumpCond::LessOrEqual => "le",
+            JumpCond::Greater => "gt",
+        }
+    }
+
+    pub fn from_suffix(suffix: &str) -> Option<JumpCond> {
+        JumpCond::ALL.into_iter().find(|cond| cond.suffix() == suffix)
+    }
+
+    fn index(&self) -> u16 {
+        JumpCond::ALL.iter().position(|cond| cond == self).unwrap() as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Register {
     A, B, C
 }
 
+impl Register {
+    pub fn name(&self) -> char {
+        match self {
+            Register::A => 'a',
+            Register::B => 'b',
+            Register::C => 'c',
+        }
+    }
+}
+
 pub enum CpuConst {
     X0000,
     X0001,
@@ -422,12 +1248,19 @@ pub enum AluInstr {
     And,
     Xor,
     Add,
+    AddWithCarry,
     Subtract,
+    SubtractWithBorrow,
     Multiply,
+    SignedMultiply,
+    SignedCompare,
+    Divide,
+    Modulo,
     Random,
     ShiftLeftVar,
     ShiftLeft(ShiftAmount),
     ShiftRightVar,
+    ShiftRightArith,
     ShiftRight(ShiftAmount),
 }
 
@@ -464,33 +1297,87 @@ pub enum CpuInstr {
     Pause,
     Resume,
     SetError,
+    GetError,
+    MoveToTrapTable,
+    TrapCounter,
+    MoveToTrapCounter,
+    ReturnFromTrap,
+    EnableInterrupts,
+    DisableInterrupts,
+    ReturnFromInterrupt,
+    WaitForInterrupt,
+    MoveToInterruptMask,
+    MoveToInterruptTable,
     Move { from: Register, to: Register},
     LoadConst,
     Const(u16),
     CpuConst(CpuConst),
     InstrCounter,
+    AddInstrCounter,
     StackCounter,
     MoveToStackCounter,
+    MoveToStackBase,
+    MoveToStackLimit,
     Input,
     Output,
     MemRead,
     MemWrite,
+    MemCopy,
+    MemSet,
+    MemReadByte,
+    MemWriteByte,
+    MemReadIndexed,
+    MemWriteIndexed,
+    MemReadStack,
+    MemWriteStack,
     Jump,
     JumpIfZero,
     JumpIfNeg,
     JumpIfNegOrZero,
     JumpIfOverflow,
+    JumpIfCarry,
+    JumpIfLess,
+    JumpIfGreaterOrEqual,
+    JumpIfLessOrEqual,
+    JumpIfGreater,
+    JumpRelative(JumpCond),
+    CallRelative,
+    Call,
+    Return,
+    ReturnFrom,
+    Push,
+    Pop,
+    Peek(u8),
+    Replace(u8),
+    Drop(u8),
+    WrapMode,
+    SaturateMode,
+    HighWord,
+    SetImmediate(u8),
+    JumpShort { cond: JumpCond, offset: i8 },
     AluInstr { instr: AluInstr, pass: bool },
+    AluImmediate { instr: AluInstr, pass: bool, val: u8 },
 }
 
 impl CpuInstr {
-    pub fn get_instr(line: usize, s: &str) -> Result<CpuInstr, String> {
+    pub fn get_instr(s: &str, map: &MemoryMap) -> Result<CpuInstr, AsmErrorKind> {
         match s {
             "wait" => Ok(CpuInstr::Wait),
             "halt" => Ok(CpuInstr::Halt),
             "pause" => Ok(CpuInstr::Pause),
             "resume" => Ok(CpuInstr::Resume),
             "seterr" => Ok(CpuInstr::SetError),
+            "geterr" => Ok(CpuInstr::GetError),
+            "mttab" => Ok(CpuInstr::MoveToTrapTable),
+            "tctr" => Ok(CpuInstr::TrapCounter),
+            "mtctr" => Ok(CpuInstr::MoveToTrapCounter),
+            "rett" => Ok(CpuInstr::ReturnFromTrap),
+            "ei" => Ok(CpuInstr::EnableInterrupts),
+            "di" => Ok(CpuInstr::DisableInterrupts),
+            "reti" => Ok(CpuInstr::ReturnFromInterrupt),
+            "wfi" => Ok(CpuInstr::WaitForInterrupt),
+            "mimask" => Ok(CpuInstr::MoveToInterruptMask),
+            "mitab" => Ok(CpuInstr::MoveToInterruptTable),
             "movab" => Ok(CpuInstr::Move { from: Register::A, to: Register::B }),
             "movba" => Ok(CpuInstr::Move { from: Register::B, to: Register::A }),
             "movac" => Ok(CpuInstr::Move { from: Register::A, to: Register::C }),
@@ -499,17 +1386,42 @@ impl CpuInstr {
             "movcb" => Ok(CpuInstr::Move { from: Register::C, to: Register::B }),
             "const" => Ok(CpuInstr::LoadConst),
             "ictr" => Ok(CpuInstr::InstrCounter),
+            "addpc" => Ok(CpuInstr::AddInstrCounter),
             "sctr" => Ok(CpuInstr::StackCounter),
             "msctr" => Ok(CpuInstr::MoveToStackCounter),
+            "msbase" => Ok(CpuInstr::MoveToStackBase),
+            "mslim" => Ok(CpuInstr::MoveToStackLimit),
             "inp" => Ok(CpuInstr::Input),
             "outp" => Ok(CpuInstr::Output),
             "memr" => Ok(CpuInstr::MemRead),
             "memw" => Ok(CpuInstr::MemWrite),
+            "mcpy" => Ok(CpuInstr::MemCopy),
+            "mset" => Ok(CpuInstr::MemSet),
+            "memrb" => Ok(CpuInstr::MemReadByte),
+            "memwb" => Ok(CpuInstr::MemWriteByte),
+            "memrx" => Ok(CpuInstr::MemReadIndexed),
+            "memwx" => Ok(CpuInstr::MemWriteIndexed),
+            "memrs" => Ok(CpuInstr::MemReadStack),
+            "memws" => Ok(CpuInstr::MemWriteStack),
             "jmp" => Ok(CpuInstr::Jump),
             "jmpz" => Ok(CpuInstr::JumpIfZero),
             "jmpn" => Ok(CpuInstr::JumpIfNeg),
             "jmpnz" => Ok(CpuInstr::JumpIfNegOrZero),
             "jmpo" => Ok(CpuInstr::JumpIfOverflow),
+            "jmpc" => Ok(CpuInstr::JumpIfCarry),
+            "jmplt" => Ok(CpuInstr::JumpIfLess),
+            "jmpge" => Ok(CpuInstr::JumpIfGreaterOrEqual),
+            "jmple" => Ok(CpuInstr::JumpIfLessOrEqual),
+            "jmpgt" => Ok(CpuInstr::JumpIfGreater),
+            "callr" => Ok(CpuInstr::CallRelative),
+            "call" => Ok(CpuInstr::Call),
+            "ret" => Ok(CpuInstr::Return),
+            "retn" => Ok(CpuInstr::ReturnFrom),
+            "push" => Ok(CpuInstr::Push),
+            "pop" => Ok(CpuInstr::Pop),
+            "wrap" => Ok(CpuInstr::WrapMode),
+            "sat" => Ok(CpuInstr::SaturateMode),
+            "hi" => Ok(CpuInstr::HighWord),
             "noop" => Ok(CpuInstr::AluInstr { instr: AluInstr::NoOp, pass: true }),
             "inc" => Ok(CpuInstr::AluInstr { instr: AluInstr::Increment, pass: false }),
             "incp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Increment, pass: true }),
@@ -525,15 +1437,28 @@ impl CpuInstr {
             "xorp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Xor, pass: true }),
             "add" => Ok(CpuInstr::AluInstr { instr: AluInstr::Add, pass: false }),
             "addp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Add, pass: true }),
+            "adc" => Ok(CpuInstr::AluInstr { instr: AluInstr::AddWithCarry, pass: false }),
+            "adcp" => Ok(CpuInstr::AluInstr { instr: AluInstr::AddWithCarry, pass: true }),
             "sub" => Ok(CpuInstr::AluInstr { instr: AluInstr::Subtract, pass: false }),
             "subp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Subtract, pass: true }),
+            "sbb" => Ok(CpuInstr::AluInstr { instr: AluInstr::SubtractWithBorrow, pass: false }),
+            "sbbp" => Ok(CpuInstr::AluInstr { instr: AluInstr::SubtractWithBorrow, pass: true }),
             "mult" => Ok(CpuInstr::AluInstr { instr: AluInstr::Multiply, pass: false }),
             "multp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Multiply, pass: true }),
+            "smult" => Ok(CpuInstr::AluInstr { instr: AluInstr::SignedMultiply, pass: false }),
+            "smultp" => Ok(CpuInstr::AluInstr { instr: AluInstr::SignedMultiply, pass: true }),
+            "scmp" => Ok(CpuInstr::AluInstr { instr: AluInstr::SignedCompare, pass: true }),
+            "div" => Ok(CpuInstr::AluInstr { instr: AluInstr::Divide, pass: false }),
+            "divp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Divide, pass: true }),
+            "mod" => Ok(CpuInstr::AluInstr { instr: AluInstr::Modulo, pass: false }),
+            "modp" => Ok(CpuInstr::AluInstr { instr: AluInstr::Modulo, pass: true }),
             "rand" => Ok(CpuInstr::AluInstr { instr: AluInstr::Random, pass: false }),
             "shl" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftLeftVar, pass: false }),
             "shlp" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftLeftVar, pass: true }),
             "shr" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftRightVar, pass: false }),
             "shrp" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftRightVar, pass: true }),
+            "sar" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftRightArith, pass: false }),
+            "sarp" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftRightArith, pass: true }),
             "shl1" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S1), pass: false }),
             "shl2" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S2), pass: false }),
             "shl3" => Ok(CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S3), pass: false }),
@@ -600,26 +1525,75 @@ impl CpuInstr {
             "set0x000f" => Ok(CpuInstr::CpuConst(CpuConst::X000F)),
             "set0x0010" => Ok(CpuInstr::CpuConst(CpuConst::X0010)),
             l => {
-                if l.starts_with('(') && l.ends_with(')') {
+                if let Some(cond) = l.strip_prefix("jmpr").and_then(JumpCond::from_suffix) {
+                    Ok(CpuInstr::JumpRelative(cond))
+                } else if l.starts_with('(') && l.ends_with(')') {
                     let middle = &l[1..(l.len() - 1)];
                     match middle.to_ascii_lowercase().as_str() {
-                        "vram" => Ok(CpuInstr::Const(cpu::VRAM_START)),
-                        "program" => Ok(CpuInstr::Const(cpu::PROGRAM_START)),
-                        "stack" => Ok(CpuInstr::Const(cpu::STACK_START)),
-                        "builtin" => Ok(CpuInstr::Const(cpu::BUILTIN_START)),
-                        "heap_meta" => Ok(CpuInstr::Const(cpu::HEAP_META_START)),
-                        "heap_data" => Ok(CpuInstr::Const(cpu::HEAP_DATA_START)),
+                        "vram" => Ok(CpuInstr::Const(map.start(Section::Vram))),
+                        "program" => Ok(CpuInstr::Const(map.start(Section::Program))),
+                        "stack" => Ok(CpuInstr::Const(map.start(Section::Stack))),
+                        "builtin" => Ok(CpuInstr::Const(map.start(Section::Builtin))),
+                        "heap_meta" => Ok(CpuInstr::Const(map.start(Section::HeapMeta))),
+                        "heap_data" => Ok(CpuInstr::Const(map.start(Section::HeapData))),
                         "success_error" => Ok(CpuInstr::Const(cpu::SUCCESS_ERROR_CODE)),
                         "stack_error" => Ok(CpuInstr::Const(cpu::STACK_OVERFLOW_ERROR_CODE)),
                         "heap_alloc_error" => Ok(CpuInstr::Const(cpu::HEAP_ALLOC_ERROR_CODE)),
                         "div_0_error" => Ok(CpuInstr::Const(cpu::DIV_ZERO_ERROR_CODE)),
+                        "invalid_instr_error" => Ok(CpuInstr::Const(cpu::INVALID_INSTR_ERROR_CODE)),
+                        "mem_access_error" => Ok(CpuInstr::Const(cpu::MEM_ACCESS_ERROR_CODE)),
+                        "illegal_op_error" => Ok(CpuInstr::Const(cpu::ILLEGAL_OP_ERROR_CODE)),
+                        "trap_table" => Ok(CpuInstr::Const(map.trap_table())),
+                        "irq_table" => Ok(CpuInstr::Const(map.irq_table())),
+                        "console_port" => Ok(CpuInstr::Const(cpu::CONSOLE_PORT)),
+                        "timer_port" => Ok(CpuInstr::Const(cpu::TIMER_PORT)),
+                        "console_packed_port" => Ok(CpuInstr::Const(cpu::CONSOLE_PACKED_PORT)),
+                        "keyboard_port" => Ok(CpuInstr::Const(cpu::KEYBOARD_PORT)),
+                        "keyboard_status_port" => Ok(CpuInstr::Const(cpu::KEYBOARD_STATUS_PORT)),
+                        "key_eof" => Ok(CpuInstr::Const(device::KEY_EOF)),
                         _ => match util::parse_u16(middle) {
                             Ok(val) => Ok(CpuInstr::Const(val)),
-                            Err(_) => return Err(format!("Line {}:\n  Invalid constant: {}", line + 1, middle)),
+                            Err(_) => Err(AsmErrorKind::InvalidConstant(middle.into())),
                         }
                     }
+                } else if let Some((name, arg)) = l.split_once(' ') {
+                    let arg = arg.trim();
+                    if let Some(cond) = name.strip_prefix("jr").and_then(JumpCond::from_suffix) {
+                        let offset = match arg.strip_prefix('-') {
+                            Some(val) => util::parse_u16(val).ok().filter(|val| *val <= 0x80).map(|val| (val as i16).wrapping_neg() as i8),
+                            None => util::parse_u16(arg).ok().filter(|val| *val <= 0x7f).map(|val| val as i8),
+                        };
+                        return match offset {
+                            Some(offset) => Ok(CpuInstr::JumpShort { cond, offset }),
+                            None => Err(AsmErrorKind::InvalidConstant(arg.into())),
+                        };
+                    }
+                    let n = match util::parse_u16(arg) {
+                        Ok(n) if n <= 0xff => n as u8,
+                        _ => return Err(AsmErrorKind::InvalidConstant(arg.into())),
+                    };
+                    let (alu, pass) = match name.strip_suffix('p') {
+                        Some(alu) => (alu, true),
+                        None => (name, false),
+                    };
+                    let alu = match alu {
+                        "addi" => Some(AluInstr::Add),
+                        "subi" => Some(AluInstr::Subtract),
+                        "andi" => Some(AluInstr::And),
+                        "ori" => Some(AluInstr::Or),
+                        "xori" => Some(AluInstr::Xor),
+                        _ => None,
+                    };
+                    match (name, alu) {
+                        (_, Some(instr)) => Ok(CpuInstr::AluImmediate { instr, pass, val: n }),
+                        ("seti", None) => Ok(CpuInstr::SetImmediate(n)),
+                        ("peek", None) => Ok(CpuInstr::Peek(n)),
+                        ("rep", None) => Ok(CpuInstr::Replace(n)),
+                        ("drop", None) => Ok(CpuInstr::Drop(n)),
+                        _ => Err(AsmErrorKind::InvalidInstruction(l.into())),
+                    }
                 } else {
-                    return Err(format!("Line {}:\n  Invalid instruction: '{}'", line + 1, l))
+                    Err(AsmErrorKind::InvalidInstruction(l.into()))
                 }
             }
         }
@@ -632,6 +1606,17 @@ impl CpuInstr {
             CpuInstr::Pause => "pause".into(),
             CpuInstr::Resume => "resume".into(),
             CpuInstr::SetError => "seterr".into(),
+            CpuInstr::GetError => "geterr".into(),
+            CpuInstr::MoveToTrapTable => "mttab".into(),
+            CpuInstr::TrapCounter => "tctr".into(),
+            CpuInstr::MoveToTrapCounter => "mtctr".into(),
+            CpuInstr::ReturnFromTrap => "rett".into(),
+            CpuInstr::EnableInterrupts => "ei".into(),
+            CpuInstr::DisableInterrupts => "di".into(),
+            CpuInstr::ReturnFromInterrupt => "reti".into(),
+            CpuInstr::WaitForInterrupt => "wfi".into(),
+            CpuInstr::MoveToInterruptMask => "mimask".into(),
+            CpuInstr::MoveToInterruptTable => "mitab".into(),
             CpuInstr::Move { from, to } => match (from, to) {
                 (Register::A, Register::B) => "movab".into(),
                 (Register::B, Register::A) => "movba".into(),
@@ -645,17 +1630,59 @@ impl CpuInstr {
             CpuInstr::Const(v) => format!("({:#06x})", v),
             CpuInstr::CpuConst(v) => format!("({:#06x})", v.value()),
             CpuInstr::InstrCounter => "ictr".into(),
+            CpuInstr::AddInstrCounter => "addpc".into(),
             CpuInstr::StackCounter => "sctr".into(),
             CpuInstr::MoveToStackCounter => "msctr".into(),
+            CpuInstr::MoveToStackBase => "msbase".into(),
+            CpuInstr::MoveToStackLimit => "mslim".into(),
             CpuInstr::Input => "inp".into(),
             CpuInstr::Output => "outp".into(),
             CpuInstr::MemRead => "memr".into(),
             CpuInstr::MemWrite => "memw".into(),
+            CpuInstr::MemCopy => "mcpy".into(),
+            CpuInstr::MemSet => "mset".into(),
+            CpuInstr::MemReadByte => "memrb".into(),
+            CpuInstr::MemWriteByte => "memwb".into(),
+            CpuInstr::MemReadIndexed => "memrx".into(),
+            CpuInstr::MemWriteIndexed => "memwx".into(),
+            CpuInstr::MemReadStack => "memrs".into(),
+            CpuInstr::MemWriteStack => "memws".into(),
             CpuInstr::Jump => "jmp".into(),
             CpuInstr::JumpIfZero => "jmpz".into(),
             CpuInstr::JumpIfNeg => "jmpn".into(),
             CpuInstr::JumpIfNegOrZero => "jmpnz".into(),
             CpuInstr::JumpIfOverflow => "jmpo".into(),
+            CpuInstr::JumpIfCarry => "jmpc".into(),
+            CpuInstr::JumpIfLess => "jmplt".into(),
+            CpuInstr::JumpIfGreaterOrEqual => "jmpge".into(),
+            CpuInstr::JumpIfLessOrEqual => "jmple".into(),
+            CpuInstr::JumpIfGreater => "jmpgt".into(),
+            CpuInstr::JumpRelative(cond) => format!("jmpr{}", cond.suffix()),
+            CpuInstr::CallRelative => "callr".into(),
+            CpuInstr::Call => "call".into(),
+            CpuInstr::Return => "ret".into(),
+            CpuInstr::ReturnFrom => "retn".into(),
+            CpuInstr::Push => "push".into(),
+            CpuInstr::Pop => "pop".into(),
+            CpuInstr::Peek(n) => format!("peek {}", n),
+            CpuInstr::Replace(n) => format!("rep {}", n),
+            CpuInstr::Drop(n) => format!("drop {}", n),
+            CpuInstr::WrapMode => "wrap".into(),
+            CpuInstr::SaturateMode => "sat".into(),
+            CpuInstr::HighWord => "hi".into(),
+            CpuInstr::SetImmediate(val) => format!("seti {}", val),
+            CpuInstr::JumpShort { cond, offset } => format!("jr{} {}", cond.suffix(), offset),
+            CpuInstr::AluImmediate { instr, pass, val } => {
+                let name = match instr {
+                    AluInstr::Add => "addi",
+                    AluInstr::Subtract => "subi",
+                    AluInstr::And => "andi",
+                    AluInstr::Or => "ori",
+                    AluInstr::Xor => "xori",
+                    _ => unreachable!("ALU instruction has no immediate form"),
+                };
+                format!("{}{} {}", name, if *pass { "p" } else { "" }, val)
+            }
             CpuInstr::AluInstr { instr, pass } => {
                 let pass = *pass;
                 match instr {
@@ -667,12 +1694,19 @@ impl CpuInstr {
                     AluInstr::And => if pass { "andp" } else { "and" } .into(),
                     AluInstr::Xor => if pass { "xorp" } else { "xor" } .into(),
                     AluInstr::Add => if pass { "addp" } else { "add" } .into(),
+                    AluInstr::AddWithCarry => if pass { "adcp" } else { "adc" } .into(),
                     AluInstr::Subtract => if pass { "subp" } else { "sub" } .into(),
+                    AluInstr::SubtractWithBorrow => if pass { "sbbp" } else { "sbb" } .into(),
                     AluInstr::Multiply => if pass { "multp" } else { "mult" } .into(),
+                    AluInstr::SignedMultiply => if pass { "smultp" } else { "smult" } .into(),
+                    AluInstr::SignedCompare => "scmp".into(),
+                    AluInstr::Divide => if pass { "divp" } else { "div" } .into(),
+                    AluInstr::Modulo => if pass { "modp" } else { "mod" } .into(),
                     AluInstr::Random => "rand" .into(),
                     AluInstr::ShiftLeftVar => if pass { "shlp" } else { "shl" } .into(),
                     AluInstr::ShiftLeft(a) => format!("shl{:#01x}{}", a.value(), if pass { "p" } else { "" }),
                     AluInstr::ShiftRightVar => if pass { "shrp" } else { "shr" } .into(),
+                    AluInstr::ShiftRightArith => if pass { "sarp" } else { "sar" } .into(),
                     AluInstr::ShiftRight(a) => format!("shr{:#01x}{}", a.value(), if pass { "p" } else { "" }),
                 }
             }
@@ -686,6 +1720,17 @@ impl CpuInstr {
             CpuInstr::Pause => 0x0002,
             CpuInstr::Resume => 0x0003,
             CpuInstr::SetError => 0x0004,
+            CpuInstr::GetError => 0x00a0,
+            CpuInstr::MoveToTrapTable => 0x00a1,
+            CpuInstr::TrapCounter => 0x00a2,
+            CpuInstr::MoveToTrapCounter => 0x00a4,
+            CpuInstr::ReturnFromTrap => 0x00a3,
+            CpuInstr::EnableInterrupts => 0x00a8,
+            CpuInstr::DisableInterrupts => 0x00a9,
+            CpuInstr::ReturnFromInterrupt => 0x00aa,
+            CpuInstr::WaitForInterrupt => 0x00ab,
+            CpuInstr::MoveToInterruptMask => 0x00ac,
+            CpuInstr::MoveToInterruptTable => 0x00ad,
             CpuInstr::Move { from, to } => match (from, to) {
                 (Register::A, Register::B) => 0x0010,
                 (Register::B, Register::A) => 0x0011,
@@ -707,8 +1752,11 @@ impl CpuInstr {
                 CpuConst::X0010 => 0x0024,
             }
             CpuInstr::InstrCounter => 0x0006,
+            CpuInstr::AddInstrCounter => 0x00cf,
             CpuInstr::StackCounter => 0x0007,
             CpuInstr::MoveToStackCounter => 0x0008,
+            CpuInstr::MoveToStackBase => 0x00b0,
+            CpuInstr::MoveToStackLimit => 0x00b1,
             CpuInstr::Input => 0x0009,
             CpuInstr::Output => 0x000a,
             CpuInstr::MemRead => 0x000b,
@@ -718,6 +1766,46 @@ impl CpuInstr {
             CpuInstr::JumpIfNeg => 0x0032,
             CpuInstr::JumpIfNegOrZero => 0x0033,
             CpuInstr::JumpIfOverflow => 0x0034,
+            CpuInstr::JumpIfCarry => 0x0035,
+            CpuInstr::JumpIfLess => 0x0036,
+            CpuInstr::JumpIfGreaterOrEqual => 0x0037,
+            CpuInstr::JumpIfLessOrEqual => 0x0038,
+            CpuInstr::JumpIfGreater => 0x0039,
+            CpuInstr::JumpRelative(cond) => 0x00c4 + cond.index(),
+            CpuInstr::CallRelative => 0x00ce,
+            CpuInstr::Call => 0x00d0,
+            CpuInstr::Return => 0x00d1,
+            CpuInstr::ReturnFrom => 0x00d2,
+            CpuInstr::Push => 0x00d4,
+            CpuInstr::Pop => 0x00d5,
+            CpuInstr::Peek(n) => 0x00d6 | (n as u16) << 8,
+            CpuInstr::Replace(n) => 0x00d7 | (n as u16) << 8,
+            CpuInstr::Drop(n) => 0x00d8 | (n as u16) << 8,
+            CpuInstr::MemCopy => 0x00bc,
+            CpuInstr::MemSet => 0x00bd,
+            CpuInstr::MemReadByte => 0x00da,
+            CpuInstr::MemWriteByte => 0x00db,
+            CpuInstr::MemReadIndexed => 0x00dc,
+            CpuInstr::MemWriteIndexed => 0x00dd,
+            CpuInstr::MemReadStack => 0x00de,
+            CpuInstr::MemWriteStack => 0x00df,
+            CpuInstr::WrapMode => 0x00b8,
+            CpuInstr::SaturateMode => 0x00b9,
+            CpuInstr::HighWord => 0x00ba,
+            CpuInstr::SetImmediate(val) => 0x00e0 | (val as u16) << 8,
+            CpuInstr::JumpShort { cond, offset } => (0x00f0 + cond.index()) | (offset as u8 as u16) << 8,
+            CpuInstr::AluImmediate { instr, pass, val } => {
+                let p: u16 = if pass { 0 } else { 1 };
+                let code = match instr {
+                    AluInstr::Add => 0x00e2,
+                    AluInstr::Subtract => 0x00e4,
+                    AluInstr::And => 0x00e6,
+                    AluInstr::Or => 0x00e8,
+                    AluInstr::Xor => 0x00ea,
+                    _ => unreachable!("ALU instruction has no immediate form"),
+                };
+                code | p | (val as u16) << 8
+            }
             CpuInstr::AluInstr { instr, pass } => {
                 let p: u16 = if pass { 0 } else { 1 };
                 match instr {
@@ -729,11 +1817,18 @@ impl CpuInstr {
                     AluInstr::And => 0x004a | p,
                     AluInstr::Xor => 0x004c | p,
                     AluInstr::Add => 0x004e | p,
+                    AluInstr::AddWithCarry => 0x005e | p,
                     AluInstr::Subtract => 0x0050 | p,
+                    AluInstr::SubtractWithBorrow => 0x0060 | p,
                     AluInstr::Multiply => 0x0052 | p,
+                    AluInstr::SignedMultiply => 0x00c2 | p,
+                    AluInstr::SignedCompare => 0x00c0,
+                    AluInstr::Divide => 0x005a | p,
+                    AluInstr::Modulo => 0x005c | p,
                     AluInstr::Random => 0x0054 | p,
                     AluInstr::ShiftLeftVar => 0x0056 | p,
                     AluInstr::ShiftRightVar => 0x0058 | p,
+                    AluInstr::ShiftRightArith => 0x0080 | p,
                     AluInstr::ShiftLeft(shift) => (0x0060 + (shift.value() << 1)) | p,
                     AluInstr::ShiftRight(shift) => (0x0080 + (shift.value() << 1)) | p,
                 }
@@ -0,0 +1,280 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::error::CpuError;
+use crate::state::{StateReader, StateWriter};
+
+pub const KEY_NONE: u16 = 0x0000;
+pub const KEY_EOF: u16 = 0xffff;
+
+pub trait Device {
+    fn name(&self) -> &str;
+
+    fn read(&mut self) -> Result<u16, CpuError> {
+        Ok(0x0000)
+    }
+
+    fn write(&mut self, _value: u16) -> Result<(), CpuError> {
+        Ok(())
+    }
+
+    // called after every cycle with the number of cycles it took
+    fn tick(&mut self, _cycles: u64) -> Option<u16> {
+        None
+    }
+
+    fn load(&mut self, _offset: u16) -> Result<u16, CpuError> {
+        self.read()
+    }
+
+    fn store(&mut self, _offset: u16, value: u16) -> Result<(), CpuError> {
+        self.write(value)
+    }
+
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), CpuError> {
+        Ok(())
+    }
+}
+
+pub struct MappedRegion {
+    pub start: u16,
+    pub len: u16,
+    pub device: Box<dyn Device>,
+}
+
+impl MappedRegion {
+    pub fn end(&self) -> usize {
+        self.start as usize + self.len as usize
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        address >= self.start && (address as usize) < self.end()
+    }
+}
+
+pub struct Console {
+    keys: KeyQueue,
+}
+
+impl Console {
+    pub fn new(keys: KeyQueue) -> Self {
+        Console { keys }
+    }
+}
+
+impl Device for Console {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    fn read(&mut self) -> Result<u16, CpuError> {
+        Ok(self.keys.pop())
+    }
+
+    fn write(&mut self, value: u16) -> Result<(), CpuError> {
+        print!("{}", value as u8 as char);
+        Ok(())
+    }
+}
+
+// two characters per word, high byte first
+pub struct PackedConsole;
+
+impl Device for PackedConsole {
+    fn name(&self) -> &str {
+        "packed console"
+    }
+
+    fn write(&mut self, value: u16) -> Result<(), CpuError> {
+        // a zero low byte pads the last word of a string with an odd length
+        print!("{}", (value >> 8) as u8 as char);
+        if value & 0x00ff != 0 {
+            print!("{}", value as u8 as char);
+        }
+        Ok(())
+    }
+}
+
+pub struct Timer {
+    irq_line: u16,
+    period: u16,
+    remaining: u16,
+}
+
+impl Timer {
+    pub fn new(irq_line: u16) -> Self {
+        Timer { irq_line, period: 0, remaining: 0 }
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn read(&mut self) -> Result<u16, CpuError> {
+        Ok(self.period)
+    }
+
+    fn write(&mut self, value: u16) -> Result<(), CpuError> {
+        self.period = value;
+        self.remaining = value;
+        Ok(())
+    }
+
+    // when memory-mapped, the first word is the period and the second the cycles remaining, which is read-only
+    fn load(&mut self, offset: u16) -> Result<u16, CpuError> {
+        match offset {
+            0 => Ok(self.period),
+            1 => Ok(self.remaining),
+            _ => Ok(0x0000),
+        }
+    }
+
+    fn store(&mut self, offset: u16, value: u16) -> Result<(), CpuError> {
+        if offset == 0 {
+            self.write(value)?;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.period);
+        state.u16(self.remaining);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), CpuError> {
+        let (period, remaining) = (state.u16()?, state.u16()?);
+        if period != 0 && remaining == 0 {
+            return Err(CpuError::InvalidState("running timer with no cycles remaining".into()));
+        }
+        self.period = period;
+        self.remaining = remaining;
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) -> Option<u16> {
+        if self.period == 0 {
+            return None;
+        }
+        if cycles < self.remaining as u64 {
+            self.remaining -= cycles as u16;
+            return None;
+        }
+        // a block instruction may pass several deadlines at once, they raise the line only once
+        let overshoot = (cycles - self.remaining as u64) % self.period as u64;
+        self.remaining = self.period - overshoot as u16;
+        Some(self.irq_line)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyQueue {
+    state: Arc<Mutex<KeyState>>,
+}
+
+#[derive(Debug, Default)]
+struct KeyState {
+    keys: VecDeque<u16>,
+    closed: bool,
+    // cleared when the keyboard raises its interrupt line
+    arrived: bool,
+}
+
+impl KeyQueue {
+    fn state(&self) -> MutexGuard<'_, KeyState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn push(&self, key: u16) {
+        let mut state = self.state();
+        state.keys.push_back(key);
+        state.arrived = true;
+    }
+
+    pub fn close(&self) {
+        self.state().closed = true;
+    }
+
+    pub fn pop(&self) -> u16 {
+        let mut state = self.state();
+        match state.keys.pop_front() {
+            Some(key) => key,
+            None if state.closed => KEY_EOF,
+            None => KEY_NONE,
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        let state = self.state();
+        if state.keys.is_empty() && state.closed {
+            KEY_EOF
+        } else {
+            state.keys.len().min(KEY_EOF as usize - 1) as u16
+        }
+    }
+
+    pub fn queued(&self) -> Vec<u16> {
+        self.state().keys.iter().copied().collect()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    pub fn replace(&self, keys: Vec<u16>, closed: bool) {
+        let mut state = self.state();
+        state.keys = keys.into();
+        state.closed = closed;
+    }
+
+    fn take_arrived(&self) -> bool {
+        std::mem::take(&mut self.state().arrived)
+    }
+}
+
+pub struct Keyboard {
+    irq_line: u16,
+    keys: KeyQueue,
+}
+
+impl Keyboard {
+    pub fn new(irq_line: u16, keys: KeyQueue) -> Self {
+        Keyboard { irq_line, keys }
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn read(&mut self) -> Result<u16, CpuError> {
+        Ok(self.keys.pop())
+    }
+
+    fn tick(&mut self, _cycles: u64) -> Option<u16> {
+        if self.keys.take_arrived() { Some(self.irq_line) } else { None }
+    }
+}
+
+pub struct KeyboardStatus {
+    keys: KeyQueue,
+}
+
+impl KeyboardStatus {
+    pub fn new(keys: KeyQueue) -> Self {
+        KeyboardStatus { keys }
+    }
+}
+
+impl Device for KeyboardStatus {
+    fn name(&self) -> &str {
+        "keyboard status"
+    }
+
+    fn read(&mut self) -> Result<u16, CpuError> {
+        Ok(self.keys.status())
+    }
+}
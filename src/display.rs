@@ -0,0 +1,270 @@
+use std::{fs, io};
+use std::path::PathBuf;
+
+pub const DISPLAY_WIDTH: usize = 128;
+pub const DISPLAY_HEIGHT: usize = 128;
+// words of VRAM used by the framebuffer, one bit per pixel
+pub const FRAMEBUFFER_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT / 16;
+
+pub const TEXT_COLUMNS: usize = 64;
+pub const TEXT_ROWS: usize = 16;
+pub const GLYPH_SIZE: usize = 8;
+// cells with an attribute of 0 are drawn with this one, light grey on black
+pub const DEFAULT_ATTRIBUTE: u8 = 0x07;
+
+// word of VRAM selecting the display mode, right after the framebuffer
+pub const DISPLAY_MODE_OFFSET: usize = FRAMEBUFFER_SIZE;
+
+pub const MONO_PALETTE: [[u8; 3]; 2] = [[0x00, 0x00, 0x00], [0xff, 0xff, 0xff]];
+// in the order of the ANSI terminal colours
+pub const TEXT_PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0xaa, 0x00, 0x00], [0x00, 0xaa, 0x00], [0xaa, 0x55, 0x00],
+    [0x00, 0x00, 0xaa], [0xaa, 0x00, 0xaa], [0x00, 0xaa, 0xaa], [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55], [0xff, 0x55, 0x55], [0x55, 0xff, 0x55], [0xff, 0xff, 0x55],
+    [0x55, 0x55, 0xff], [0xff, 0x55, 0xff], [0x55, 0xff, 0xff], [0xff, 0xff, 0xff],
+];
+
+// printable ASCII characters 0x20..=0x7f, one byte per row of a glyph with the least significant bit as its leftmost
+// pixel
+pub const FONT: [[u8; GLYPH_SIZE]; 96] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3c, 0x3c, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7f, 0x36, 0x7f, 0x36, 0x36, 0x00], // '#'
+    [0x0c, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x0c, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0c, 0x66, 0x63, 0x00], // '%'
+    [0x1c, 0x36, 0x1c, 0x6e, 0x3b, 0x33, 0x6e, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '\''
+    [0x18, 0x0c, 0x06, 0x06, 0x06, 0x0c, 0x18, 0x00], // '('
+    [0x06, 0x0c, 0x18, 0x18, 0x18, 0x0c, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3c, 0xff, 0x3c, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0c, 0x0c, 0x3f, 0x0c, 0x0c, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0c, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3e, 0x63, 0x73, 0x7b, 0x6f, 0x67, 0x3e, 0x00], // '0'
+    [0x0c, 0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x3f, 0x00], // '1'
+    [0x1e, 0x33, 0x30, 0x1c, 0x06, 0x33, 0x3f, 0x00], // '2'
+    [0x1e, 0x33, 0x30, 0x1c, 0x30, 0x33, 0x1e, 0x00], // '3'
+    [0x38, 0x3c, 0x36, 0x33, 0x7f, 0x30, 0x78, 0x00], // '4'
+    [0x3f, 0x03, 0x1f, 0x30, 0x30, 0x33, 0x1e, 0x00], // '5'
+    [0x1c, 0x06, 0x03, 0x1f, 0x33, 0x33, 0x1e, 0x00], // '6'
+    [0x3f, 0x33, 0x30, 0x18, 0x0c, 0x0c, 0x0c, 0x00], // '7'
+    [0x1e, 0x33, 0x33, 0x1e, 0x33, 0x33, 0x1e, 0x00], // '8'
+    [0x1e, 0x33, 0x33, 0x3e, 0x30, 0x18, 0x0e, 0x00], // '9'
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x00], // ':'
+    [0x00, 0x0c, 0x0c, 0x00, 0x00, 0x0c, 0x0c, 0x06], // ';'
+    [0x18, 0x0c, 0x06, 0x03, 0x06, 0x0c, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3f, 0x00, 0x00, 0x3f, 0x00, 0x00], // '='
+    [0x06, 0x0c, 0x18, 0x30, 0x18, 0x0c, 0x06, 0x00], // '>'
+    [0x1e, 0x33, 0x30, 0x18, 0x0c, 0x00, 0x0c, 0x00], // '?'
+    [0x3e, 0x63, 0x7b, 0x7b, 0x7b, 0x03, 0x1e, 0x00], // '@'
+    [0x0c, 0x1e, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x00], // 'A'
+    [0x3f, 0x66, 0x66, 0x3e, 0x66, 0x66, 0x3f, 0x00], // 'B'
+    [0x3c, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3c, 0x00], // 'C'
+    [0x1f, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1f, 0x00], // 'D'
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x46, 0x7f, 0x00], // 'E'
+    [0x7f, 0x46, 0x16, 0x1e, 0x16, 0x06, 0x0f, 0x00], // 'F'
+    [0x3c, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7c, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3f, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1e, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0f, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7f, 0x00], // 'L'
+    [0x63, 0x77, 0x7f, 0x7f, 0x6b, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6f, 0x7b, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1c, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1c, 0x00], // 'O'
+    [0x3f, 0x66, 0x66, 0x3e, 0x06, 0x06, 0x0f, 0x00], // 'P'
+    [0x1e, 0x33, 0x33, 0x33, 0x3b, 0x1e, 0x38, 0x00], // 'Q'
+    [0x3f, 0x66, 0x66, 0x3e, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1e, 0x33, 0x07, 0x0e, 0x38, 0x33, 0x1e, 0x00], // 'S'
+    [0x3f, 0x2d, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3f, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6b, 0x7f, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1c, 0x1c, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1e, 0x0c, 0x0c, 0x1e, 0x00], // 'Y'
+    [0x7f, 0x63, 0x31, 0x18, 0x4c, 0x66, 0x7f, 0x00], // 'Z'
+    [0x1e, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1e, 0x00], // '['
+    [0x03, 0x06, 0x0c, 0x18, 0x30, 0x60, 0x40, 0x00], // '\\'
+    [0x1e, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1e, 0x00], // ']'
+    [0x08, 0x1c, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff], // '_'
+    [0x0c, 0x0c, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1e, 0x30, 0x3e, 0x33, 0x6e, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3e, 0x66, 0x66, 0x3b, 0x00], // 'b'
+    [0x00, 0x00, 0x1e, 0x33, 0x03, 0x33, 0x1e, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3e, 0x33, 0x33, 0x6e, 0x00], // 'd'
+    [0x00, 0x00, 0x1e, 0x33, 0x3f, 0x03, 0x1e, 0x00], // 'e'
+    [0x1c, 0x36, 0x06, 0x0f, 0x06, 0x06, 0x0f, 0x00], // 'f'
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x1f], // 'g'
+    [0x07, 0x06, 0x36, 0x6e, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0c, 0x00, 0x0e, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1e], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1e, 0x36, 0x67, 0x00], // 'k'
+    [0x0e, 0x0c, 0x0c, 0x0c, 0x0c, 0x0c, 0x1e, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7f, 0x7f, 0x6b, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1f, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1e, 0x33, 0x33, 0x33, 0x1e, 0x00], // 'o'
+    [0x00, 0x00, 0x3b, 0x66, 0x66, 0x3e, 0x06, 0x0f], // 'p'
+    [0x00, 0x00, 0x6e, 0x33, 0x33, 0x3e, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3b, 0x6e, 0x66, 0x06, 0x0f, 0x00], // 'r'
+    [0x00, 0x00, 0x3e, 0x03, 0x1e, 0x30, 0x1f, 0x00], // 's'
+    [0x08, 0x0c, 0x3e, 0x0c, 0x0c, 0x2c, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6e, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1e, 0x0c, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6b, 0x7f, 0x7f, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1c, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3e, 0x30, 0x1f], // 'y'
+    [0x00, 0x00, 0x3f, 0x19, 0x0c, 0x26, 0x3f, 0x00], // 'z'
+    [0x38, 0x0c, 0x0c, 0x07, 0x0c, 0x0c, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0c, 0x0c, 0x38, 0x0c, 0x0c, 0x07, 0x00], // '}'
+    [0x6e, 0x3b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // del
+];
+
+const BRAILLE_BASE: u32 = 0x2800;
+// bit of a braille character for each dot, indexed by [y][x] within the 2x4 cell
+const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayMode {
+    Graphics,
+    Text,
+}
+
+impl DisplayMode {
+    pub fn of(vram: &[u16]) -> DisplayMode {
+        match vram.get(DISPLAY_MODE_OFFSET) {
+            Some(1) => DisplayMode::Text,
+            _ => DisplayMode::Graphics,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisplayMode::Graphics => "graphics",
+            DisplayMode::Text => "text",
+        }
+    }
+}
+
+// rows are DISPLAY_WIDTH / 16 words long and the most significant bit of a word is its leftmost pixel
+pub fn pixel(vram: &[u16], x: usize, y: usize) -> bool {
+    let word = vram.get(y * DISPLAY_WIDTH / 16 + x / 16).copied().unwrap_or(0);
+    word & (0x8000 >> (x % 16)) != 0
+}
+
+// the low byte of a cell is the character and the high byte its attribute
+pub fn cell(vram: &[u16], column: usize, row: usize) -> (u8, u8) {
+    let word = vram.get(row * TEXT_COLUMNS + column).copied().unwrap_or(0);
+    let attribute = (word >> 8) as u8;
+    (word as u8, if attribute == 0 { DEFAULT_ATTRIBUTE } else { attribute })
+}
+
+pub fn glyph(c: u8) -> [u8; GLYPH_SIZE] {
+    match c {
+        0x20..=0x7f => FONT[(c - 0x20) as usize],
+        _ => [0; GLYPH_SIZE],
+    }
+}
+
+pub fn render(vram: &[u16]) -> String {
+    match DisplayMode::of(vram) {
+        DisplayMode::Graphics => render_graphics(vram),
+        DisplayMode::Text => render_text(vram),
+    }
+}
+
+fn render_graphics(vram: &[u16]) -> String {
+    let mut s = String::with_capacity((DISPLAY_WIDTH / 2 + 1) * (DISPLAY_HEIGHT / 4) * 3);
+    for row in (0..DISPLAY_HEIGHT).step_by(4) {
+        for col in (0..DISPLAY_WIDTH).step_by(2) {
+            let mut dots = 0u8;
+            for (dy, bits) in BRAILLE_DOTS.iter().enumerate() {
+                for (dx, bit) in bits.iter().enumerate() {
+                    if pixel(vram, col + dx, row + dy) {
+                        dots |= bit;
+                    }
+                }
+            }
+            s.push(char::from_u32(BRAILLE_BASE + dots as u32).unwrap_or(' '));
+        }
+        s.push('\n');
+    }
+    s
+}
+
+fn render_text(vram: &[u16]) -> String {
+    let mut s = String::new();
+    for row in 0..TEXT_ROWS {
+        let mut last_attribute = None;
+        for column in 0..TEXT_COLUMNS {
+            let (c, attribute) = cell(vram, column, row);
+            if last_attribute != Some(attribute) {
+                s.push_str(&format!("\x1b[{};{}m", ansi_colour(attribute & 0x0f, 30), ansi_colour(attribute >> 4, 40)));
+                last_attribute = Some(attribute);
+            }
+            s.push(if (0x20..0x7f).contains(&c) { c as char } else { ' ' });
+        }
+        s.push_str("\x1b[0m\n");
+    }
+    s
+}
+
+fn ansi_colour(colour: u8, base: u8) -> u8 {
+    if colour < 8 { base + colour } else { base + 60 + colour - 8 }
+}
+
+pub fn to_ppm(vram: &[u16]) -> Vec<u8> {
+    match DisplayMode::of(vram) {
+        DisplayMode::Graphics => encode_ppm(DISPLAY_WIDTH, DISPLAY_HEIGHT, |x, y| MONO_PALETTE[pixel(vram, x, y) as usize]),
+        DisplayMode::Text => encode_ppm(TEXT_COLUMNS * GLYPH_SIZE, TEXT_ROWS * GLYPH_SIZE, |x, y| {
+            let (c, attribute) = cell(vram, x / GLYPH_SIZE, y / GLYPH_SIZE);
+            let set = glyph(c)[y % GLYPH_SIZE] & (1 << (x % GLYPH_SIZE)) != 0;
+            TEXT_PALETTE[(if set { attribute & 0x0f } else { attribute >> 4 }) as usize]
+        }),
+    }
+}
+
+fn encode_ppm(width: usize, height: usize, colour: impl Fn(usize, usize) -> [u8; 3]) -> Vec<u8> {
+    let mut bytes = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    bytes.reserve(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            bytes.extend_from_slice(&colour(x, y));
+        }
+    }
+    bytes
+}
+
+pub struct FrameRecorder {
+    dir: PathBuf,
+    interval: u64,
+    next_cycle: u64,
+    frames: usize,
+}
+
+impl FrameRecorder {
+    pub fn new(dir: PathBuf, interval: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(FrameRecorder { dir, interval: interval.max(1), next_cycle: 0, frames: 0 })
+    }
+
+    pub fn record(&mut self, vram: &[u16], cycle: u64) -> io::Result<()> {
+        if cycle < self.next_cycle {
+            return Ok(());
+        }
+        fs::write(self.dir.join(format!("frame_{:06}.ppm", self.frames)), to_ppm(vram))?;
+        self.frames += 1;
+        // block instructions can skip past a multiple, the late frame doesn't shift the following ones
+        self.next_cycle = (cycle / self.interval + 1) * self.interval;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+}
@@ -0,0 +1,111 @@
+use std::{error, fmt};
+use crate::cpu::{Register, Trap};
+use crate::memory::{Access, Section};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuError {
+    InvalidInstruction { word: u16, address: u16 },
+    OutOfBounds { address: usize, size: usize },
+    AccessViolation { address: u16, section: Section, access: Access },
+    IllegalMove { register: Register },
+    UnknownPort { port: u16 },
+    RegionConflict { start: u16, len: u16 },
+    InvalidState(String),
+    StackOverflow { counter: u16, limit: u16 },
+    StackUnderflow { counter: u16, base: u16 },
+    DivideByZero,
+    ConstWithoutFlag,
+    ProgramTooLarge { size: usize, max: usize },
+    BuiltinTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::InvalidInstruction { word, address } => write!(f, "Failed to parse instruction {:#06x} at address {:#06x}", word, address),
+            CpuError::OutOfBounds { address, size } => write!(f, "Memory address {:#06x} out of range of memory size {:#06x}", address, size),
+            CpuError::AccessViolation { address, section, access } => write!(f, "Access violation: {} of address {:#06x} in section {} is not permitted", access, address, section),
+            CpuError::IllegalMove { register } => write!(f, "Can't move register {} to itself", register.name()),
+            CpuError::UnknownPort { port } => write!(f, "No device attached to port {:#06x}", port),
+            CpuError::RegionConflict { start, len } => write!(f, "Memory-mapped region {:#06x}..{:#06x} is empty, exceeds memory or overlaps another region", start, *start as usize + *len as usize),
+            CpuError::InvalidState(reason) => write!(f, "Invalid save state: {}", reason),
+            CpuError::StackOverflow { counter, limit } => write!(f, "Stack overflow: stack counter {:#06x} exceeds stack limit {:#06x}", counter, limit),
+            CpuError::StackUnderflow { counter, base } => write!(f, "Stack underflow: stack counter {:#06x} is below stack base {:#06x}", counter, base),
+            CpuError::DivideByZero => write!(f, "Division by zero"),
+            CpuError::ConstWithoutFlag => write!(f, "Tried to load a constant without Load Constant Flag being set"),
+            CpuError::ProgramTooLarge { size, max } => write!(f, "Program size {} exceeds maximum size of {}", size, max),
+            CpuError::BuiltinTooLarge { size, max } => write!(f, "Built-in program size {} exceeds maximum size of {}", size, max),
+        }
+    }
+}
+
+impl CpuError {
+    pub fn trap(&self) -> Option<Trap> {
+        match self {
+            CpuError::InvalidInstruction { .. } => Some(Trap::InvalidInstruction),
+            CpuError::OutOfBounds { .. } | CpuError::AccessViolation { .. } => Some(Trap::MemoryAccess),
+            CpuError::IllegalMove { .. } | CpuError::UnknownPort { .. } | CpuError::ConstWithoutFlag => Some(Trap::IllegalOperation),
+            CpuError::StackOverflow { .. } | CpuError::StackUnderflow { .. } => Some(Trap::StackOverflow),
+            CpuError::DivideByZero => Some(Trap::DivideByZero),
+            CpuError::RegionConflict { .. } | CpuError::InvalidState(_) | CpuError::ProgramTooLarge { .. } | CpuError::BuiltinTooLarge { .. } => None,
+        }
+    }
+}
+
+impl error::Error for CpuError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub address: u16,
+    pub word: u16,
+    pub error: CpuError,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fault at {:#06x} executing {:#06x}: {}", self.address, self.word, self.error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.path, self.line, self.column, self.kind)
+    }
+}
+
+impl error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrorKind {
+    ReadFile { path: String, message: String },
+    InvalidMacro(String),
+    MacroArity { name: String, args: usize },
+    InvalidLabel(String),
+    UnknownLabel(String),
+    InvalidJump(String),
+    InvalidInstruction(String),
+    InvalidConstant(String),
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::ReadFile { path, message } => write!(f, "Failed to read file {}: {}", path, message),
+            AsmErrorKind::InvalidMacro(name) => write!(f, "Invalid macro '{}'", name),
+            AsmErrorKind::MacroArity { name, args } => write!(f, "Invalid number of arguments for macro '{}': {}", name, args),
+            AsmErrorKind::InvalidLabel(label) => write!(f, "Invalid label '{}'", label),
+            AsmErrorKind::UnknownLabel(label) => write!(f, "Label '{}' doesn't exist", label),
+            AsmErrorKind::InvalidJump(jump) => write!(f, "Invalid jump instruction: '{}'", jump),
+            AsmErrorKind::InvalidInstruction(instr) => write!(f, "Invalid instruction: '{}'", instr),
+            AsmErrorKind::InvalidConstant(val) => write!(f, "Invalid constant: {}", val),
+        }
+    }
+}
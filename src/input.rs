@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use crate::device::KeyQueue;
+
+pub const STOP_KEY: u8 = 0x03;
+
+// the only reader of stdin, so the CLI and the keyboard can share it without blocking the emulator
+pub struct Input {
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Input {
+    pub fn stdin() -> Input {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buf = [0u8; 256];
+            // a read error ends the input just like the end of the stream
+            while let Ok(n @ 1..) = stdin.read(&mut buf) {
+                if sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Input { receiver, pending: VecDeque::new(), closed: false }
+    }
+
+    pub fn read_line(&mut self) -> Option<String> {
+        loop {
+            if let Some(i) = self.pending.iter().position(|b| *b == b'\n') {
+                let line = self.pending.drain(..=i).collect::<Vec<_>>();
+                return Some(String::from_utf8_lossy(&line).into_owned());
+            }
+            match self.receiver.recv() {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(_) => {
+                    self.closed = true;
+                    if self.pending.is_empty() {
+                        return None;
+                    }
+                    return Some(String::from_utf8_lossy(&self.pending.drain(..).collect::<Vec<_>>()).into_owned());
+                }
+            }
+        }
+    }
+
+    pub fn next_byte(&mut self) -> Option<u8> {
+        if self.pending.is_empty() {
+            loop {
+                match self.receiver.try_recv() {
+                    Ok(bytes) => self.pending.extend(bytes),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+pub fn feed_keys(mut reader: impl Read + Send + 'static, keys: KeyQueue) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            for byte in &buf[..n] {
+                keys.push(*byte as u16);
+            }
+        }
+        keys.close();
+    });
+}
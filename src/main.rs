@@ -1,32 +1,138 @@
-use std;
-use std::{io, fs, path, env, ffi, thread};
-use std::thread::JoinHandle;
-use std::time::Duration;
+use std::{io, fs, path, env, ffi, process, thread};
+use std::time::{Duration, Instant};
 use crate::cpu::{Cpu};
+use crate::display::FrameRecorder;
+use crate::error::CpuError;
+use crate::input::Input;
+use crate::memory::{MemoryMap, Permissions, ProtectionMap, Section};
 
 mod cpu;
+mod device;
+mod display;
+mod error;
+mod input;
+mod memory;
 mod parse;
+mod rng;
+mod state;
+mod terminal;
 mod util;
 
 pub const SOURCE_FILE_EXTENSION: &str = ".instr";
 pub const COMPILED_FILE_EXTENSION: &str = ".ember";
+pub const DISPLAY_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
 
-fn setup(input: Vec<u8>) -> Result<Cpu, String> {
+fn setup(input: Vec<u8>, memory_map: MemoryMap, seed: u64) -> Result<Cpu, CpuError> {
     let mut code = vec![0u16; input.len() >> 1];
 
     for (i, v) in input.iter().enumerate().step_by(2) {
         code[i >> 1] = ((*v as u16) << 8) | (input[i + 1] as u16);
     }
 
-    Cpu::new(code, cpu::BUILTIN_SUBROUTINES.to_vec())
+    Cpu::new(code, cpu::BUILTIN_SUBROUTINES.to_vec(), memory_map, seed)
+}
+
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|arg| *arg == name) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        }
+        Some(_) => Err(format!("Missing value for option {}", name)),
+        None => Ok(None),
+    }
+}
+
+fn take_flag(args: &mut Vec<&str>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| *arg != name);
+    args.len() != len
+}
+
+pub struct RunOptions {
+    headless: bool,
+    max_cycles: Option<u64>,
+    frames: Option<FrameRecorder>,
+    final_frame: Option<String>,
+    input: Option<String>,
+    seed: u64,
+    state: Option<String>,
+}
+
+impl RunOptions {
+    fn take(args: &mut Vec<&str>) -> Result<RunOptions, String> {
+        let headless = take_flag(args, "--headless");
+        let max_cycles = take_option(args, "--cycles")?.map(util::parse_u64).transpose()?;
+        let frames_dir = take_option(args, "--frames")?;
+        let frame_every = take_option(args, "--frame-every")?.map(util::parse_u64).transpose()?;
+        let final_frame = take_option(args, "--ppm")?.map(String::from);
+        let input = take_option(args, "--input")?.map(String::from);
+        let seed = take_option(args, "--seed")?.map(util::parse_u64).transpose()?.unwrap_or_else(rand::random);
+        let state = take_option(args, "--state")?.map(String::from);
+        let frames = match (frames_dir, frame_every) {
+            (Some(dir), Some(interval)) => Some(
+                FrameRecorder::new(path::PathBuf::from(dir), interval)
+                    .map_err(|err| format!("Failed to create frame directory {}: {}", dir, err))?
+            ),
+            (None, None) => None,
+            _ => return Err("Options --frames and --frame-every must be used together".to_string()),
+        };
+        Ok(RunOptions { headless, max_cycles, frames, final_frame, input, seed, state })
+    }
+}
+
+fn start(mut cpu: Cpu, path: &str, options: RunOptions) {
+    if let Some(state_path) = &options.state {
+        if let Err(err) = load_state(&mut cpu, state_path) {
+            eprintln!("Failed to load save state {}:\n{}", state_path, err);
+            return;
+        }
+        println!("[i] Loaded save state {} at cycle {}", state_path, cpu.get_cycle());
+    }
+    match &options.input {
+        Some(input_path) => match fs::File::open(input_path) {
+            Ok(file) => input::feed_keys(file, cpu.get_keys()),
+            Err(err) => {
+                eprintln!("Failed to open input file {}:\n{}", input_path, err);
+                return;
+            }
+        },
+        // without the CLI, stdin is all keyboard input
+        None if options.headless => input::feed_keys(io::stdin(), cpu.get_keys()),
+        None => {}
+    }
+    println!("[i] RNG seed: {:#018x}", cpu.get_rng().seed());
+    if options.headless {
+        if !run_headless(cpu, options) {
+            process::exit(1);
+        }
+    } else {
+        run_emulator(cpu, path, options);
+    }
 }
 
 fn main() {
     let args = Vec::from_iter(env::args());
-    let args: Vec<&str> = args.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+    let mut args: Vec<&str> = args.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+
+    // comp [--pic] [--map <path>] <inpath> <outpath>
+    // run [--map <path>] [--input <path>] [--seed <n>] [--state <path>] [--headless] [--cycles <n>] [--frames <dir> --frame-every <n>] [--ppm <path>] <path>
 
-    // comp <inpath> <outpath>
-    // run <path>
+    let memory_map = match take_option(&mut args, "--map") {
+        Ok(Some(map_path)) => match fs::read_to_string(map_path).map_err(|err| err.to_string()).and_then(|s| MemoryMap::parse(&s)) {
+            Ok(map) => map,
+            Err(err) => {
+                eprintln!("Failed to load memory map {}:\n{}", map_path, err);
+                return;
+            }
+        },
+        Ok(None) => MemoryMap::default(),
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
 
     if args.len() < 2 {
         eprintln!("Invalid arguments");
@@ -67,7 +173,7 @@ fn main() {
                         path::Path::new(new_path.as_os_str())
                     };
 
-                    let output_string = result.join("\n");
+                    let output_string = result.iter().map(|line| line.text.as_str()).collect::<Vec<_>>().join("\n");
 
                     match fs::write(output_path, output_string) {
                         Ok(_) => {}
@@ -81,13 +187,14 @@ fn main() {
                 }
                 Err(err) => {
                     eprintln!("Failed to normalize file {}:\n{}", args[2], err);
-                    return;
                 }
             }
         }
         "comp" => {
+            // position-independent images can be loaded at any address with the 'img' command
+            let pic = take_flag(&mut args, "--pic");
             if args.len() < 3 || args.len() > 4 {
-                eprintln!("Invalid arguments, correct syntax: comp <inpath> [outpath]");
+                eprintln!("Invalid arguments, correct syntax: comp [--pic] [--map <path>] <inpath> [outpath]");
                 return;
             }
 
@@ -99,7 +206,8 @@ fn main() {
                 }
             };
 
-            match parse::compile(cpu::PROGRAM_START, input.as_str(), args[2]) {
+            let address_start = if pic { None } else { Some(memory_map.start(Section::Program)) };
+            match parse::compile(address_start, input.as_str(), args[2], &memory_map) {
                 Ok(result) => {
                     let new_path;
                     let output_path = if args.len() == 4 {
@@ -137,13 +245,19 @@ fn main() {
                 }
                 Err(err) => {
                     eprintln!("Failed to compile file {}:\n{}", args[2], err);
-                    return;
                 }
             }
         },
         "run" => {
+            let options = match RunOptions::take(&mut args) {
+                Ok(options) => options,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            };
             if args.len() != 3 {
-                eprintln!("Invalid arguments, correct syntax: run <path>");
+                eprintln!("Invalid arguments, correct syntax: run [--map <path>] [--input <path>] [--seed <n>] [--state <path>] [--headless] [--cycles <n>] [--frames <dir> --frame-every <n>] [--ppm <path>] <path>");
                 return;
             }
 
@@ -156,7 +270,7 @@ fn main() {
                     }
                 };
 
-                match parse::compile(cpu::PROGRAM_START, input.as_str(), args[2]) {
+                match parse::compile(Some(memory_map.start(Section::Program)), input.as_str(), args[2], &memory_map) {
                     Ok(result) => {
                         let mut bytes = vec![0u8; result.len() * 2];
                         for (i, v) in result.iter().enumerate() {
@@ -164,18 +278,17 @@ fn main() {
                             bytes[i * 2 + 1] = (v & 0xff) as u8;
                         }
 
-                        let cpu = match setup(bytes) {
+                        let cpu = match setup(bytes, memory_map, options.seed) {
                             Ok(cpu) => cpu,
                             Err(err) => {
                                 eprintln!("Error setting up cpu emulator:\n{}", err);
                                 return;
                             },
                         };
-                        run_emulator(cpu, args[2]);
+                        start(cpu, args[2], options);
                     }
                     Err(err) => {
                         eprintln!("Failed to compile file {}:\n{}", args[2], err);
-                        return;
                     }
                 }
             } else if args[2].ends_with(COMPILED_FILE_EXTENSION) {
@@ -191,17 +304,16 @@ fn main() {
                     return;
                 }
 
-                let cpu = match setup(input) {
+                let cpu = match setup(input, memory_map, options.seed) {
                     Ok(cpu) => cpu,
                     Err(err) => {
                         eprintln!("Error setting up cpu emulator:\n{}", err);
                         return;
                     },
                 };
-                run_emulator(cpu, args[2]);
+                start(cpu, args[2], options);
             } else {
                 eprintln!("Unknown input file type: {}", args[2]);
-                return;
             }
         },
         arg => {
@@ -210,39 +322,89 @@ fn main() {
     }
 }
 
-pub fn run_emulator(mut cpu: Cpu, path: &str) {
-    let mut run_thread: Option<JoinHandle<()>> = None;
+fn run_headless(mut cpu: Cpu, mut options: RunOptions) -> bool {
+    let mut ok = true;
+    while cpu.is_running() && options.max_cycles.is_none_or(|max| cpu.get_cycle() < max) {
+        if cpu.cycle().is_err() {
+            ok = false;
+            break;
+        }
+        if let Some(frames) = &mut options.frames {
+            if let Err(err) = frames.record(cpu.get_vram(), cpu.get_cycle()) {
+                eprintln!("[!] Failed to write frame:\n  {}", err);
+                return false;
+            }
+        }
+    }
+    match cpu.get_fault() {
+        Some(fault) => eprintln!("[!] CPU stopped:\n  {}", fault),
+        None => println!("[i] CPU stopped after {} cycles", cpu.get_cycle()),
+    }
+    println!("{}", cpu.registers_info());
+    if let Some(frames) = &options.frames {
+        println!("[i] Wrote {} frames", frames.frames());
+    }
+    if let Some(path) = &options.final_frame {
+        if let Err(err) = fs::write(path, display::to_ppm(cpu.get_vram())) {
+            eprintln!("[!] Failed to write frame to {}:\n  {}", path, err);
+            return false;
+        }
+    }
+    ok
+}
+
+pub fn run_emulator(mut cpu: Cpu, path: &str, options: RunOptions) {
+    let mut frames = options.frames;
+    // the keyboard reads keys typed while running, unless it reads a file
+    let keys_from_stdin = options.input.is_none();
+    let keys = cpu.get_keys();
+    let mut input = Input::stdin();
+    let mut running: Option<Running> = None;
     let mut run_delay = 0u64;
     let mut auto_info = false;
+    let mut auto_display = false;
+    let mut last_frame = Instant::now();
     loop {
-        if let Some(thread) = &mut run_thread {
-            if thread.is_finished() {
-                run_thread = None;
+        if running.is_some() {
+            let mut stop = false;
+            while let Some(byte) = input.next_byte() {
+                if keys_from_stdin && byte != input::STOP_KEY {
+                    keys.push(byte as u16);
+                } else if byte == input::STOP_KEY || byte == b'\n' {
+                    stop = true;
+                    break;
+                }
+            }
+            if keys_from_stdin && input.is_closed() {
+                keys.close();
+            }
+            if stop {
+                running = None;
                 println!("[i] Stopped running!");
             } else if cpu.is_running() {
-                match cpu.cycle() {
-                    Ok(_) => {}
-                    Err(err) => {
-                        eprintln!("[!] Error cycling CPU:\n  {}", err);
-                        return;
-                    }
+                if cpu.cycle().is_err() {
+                    print_fault(&cpu);
+                    running = None;
+                    continue;
                 }
+                record_frame(&cpu, &mut frames);
                 if auto_info { println!("{}", cpu.registers_info()); }
+                if auto_display && last_frame.elapsed() >= DISPLAY_REFRESH_INTERVAL {
+                    // redraw in place by moving the cursor home and clearing the screen first
+                    print!("\x1b[H\x1b[2J{}", display::render(cpu.get_vram()));
+                    last_frame = Instant::now();
+                }
                 if run_delay > 0 { thread::sleep(Duration::from_millis(run_delay)); }
             } else {
+                running = None;
                 println!("[i] CPU paused");
                 println!("[i] CPU info:\n{}", cpu.registers_info());
-                run_thread = None;
             }
         } else {
-            let mut s = String::new();
-            match io::stdin().read_line(&mut s) {
-                Ok(_) => {}
-                Err(err) => {
-                    eprintln!("[!] Error while reading user input:\n  {}", err);
-                    return;
-                }
-            }
+            let s = match input.read_line() {
+                Some(s) => s,
+                None => return,
+            };
             let s = s.trim();
 
             match s {
@@ -251,91 +413,147 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
                     "[i] Available commands:\n    \
                     q                      - exits the process\n    \
                     s                      - steps the cpu one cycle\n    \
+                    cont                   - resumes a paused or faulted cpu, re-reading the current instruction from memory\n    \
                     dir                    - prints the working directory of the cpu\n    \
                     i                      - prints cpu info\n    \
                     ti                     - toggle automatically printing info after commands\n    \
-                    run [delay]            - run the cpu continuously with an optional delay (in milliseconds) between each cycle (exit by pressing any key)\n    \
+                    disp                   - renders VRAM in its current display mode\n    \
+                    td                     - toggle automatically refreshing the display while running\n    \
+                    ppm <path>             - writes the display to a PPM image\n    \
+                    save <path>            - writes the full cpu state to a save state file\n    \
+                    load <path>            - restores the cpu state from a save state file\n    \
+                    run [delay]            - run the cpu continuously with an optional delay (in milliseconds) between each cycle, typed keys go to the keyboard (stop with Ctrl-C, or enter when using 'run --input')\n    \
                     mem                    - prints the entire emulator memory\n    \
                     sec <section>          - prints a section of the emulator memory\n    \
                     prog                   - prints the contents of the program memory section\n    \
                     stack                  - prints the contents of the stack memory section\n    \
+                    map                    - prints the memory map\n    \
+                    seed                   - prints the seed and state of the random number generator\n    \
+                    seed <n>               - reseeds the random number generator\n    \
+                    dev                    - lists the devices attached to each port and memory-mapped region\n    \
+                    mmio <port> <address> <length> - moves the device attached to a port into a memory-mapped region\n    \
                     get <address>          - gets the value at the specified address\n    \
                     get <register>         - gets the value of the specified register\n    \
                     set <address> <value>  - sets the value at the specified address\n    \
-                    set <register> <value> - sets the value of the specified register\n    \
-                    do <instruction>       - executes the given instruction"
+                    set <register> <value> - sets the value of the specified register (a, b, c or ictr)\n    \
+                    do <instruction>       - executes the given instruction\n    \
+                    img <path> <address>   - loads a compiled image into memory at the specified address\n    \
+                    prot                   - prints the memory protection map\n    \
+                    prot on|off            - enables the default memory protection map or disables memory protection\n    \
+                    prot <section> <rwx>   - sets the permissions of a memory section, e.g. 'prot heap_data rw-'"
                 ),
                 "s" | "" => {
-                    if !cpu.is_running() {
+                    if cpu.get_fault().is_some() {
+                        eprintln!("[!] Failed to step; CPU is stopped at a fault, use 'cont' to retry the instruction");
+                    } else if !cpu.is_running() {
                         eprintln!("[!] Failed to step; CPU is halted!");
+                    } else if cpu.cycle().is_err() {
+                        print_fault(&cpu);
                     } else {
-                        match cpu.cycle() {
-                            Ok(_) => {}
-                            Err(err) => {
-                                eprintln!("[!] Error stepping CPU:\n  {}", err);
-                                return;
-                            }
-                        }
+                        record_frame(&cpu, &mut frames);
                     }
                     if auto_info { println!("{}", cpu.registers_info()); }
                 }
                 "i" => {
                     println!("[!] CPU info:\n{}", cpu.registers_info());
+                    if let Some(fault) = cpu.get_fault() {
+                        println!("[!] {}", fault);
+                    }
+                }
+                "cont" => {
+                    cpu.resume();
+                    println!("[i] CPU resumed at {:#06x}", cpu.get_instr_counter());
+                    if auto_info { println!("{}", cpu.registers_info()); }
                 }
                 "ti" => {
                     auto_info = !auto_info;
                     println!("[i] Auto info toggled {}", if auto_info { "on" } else { "off" });
-                    if auto_info { if auto_info { println!("{}", cpu.registers_info()) } }
+                    if auto_info { println!("{}", cpu.registers_info()) }
                 }
                 "dir" => {
                     println!("[i] Current working directory: {}", path);
                 }
                 "disp" => {
-
+                    println!("[i] Display ({} mode):", display::DisplayMode::of(cpu.get_vram()).name());
+                    print!("{}", display::render(cpu.get_vram()));
+                }
+                "td" => {
+                    auto_display = !auto_display;
+                    println!("[i] Auto display toggled {}", if auto_display { "on" } else { "off" });
                 }
                 "prog" => {
                     println!("[i] Program memory dump:");
-                    for i in (cpu::PROGRAM_START..cpu::STACK_START).step_by(0x0100) {
-                        println!("{}", cpu.partial_mem_dump(i))
+                    let map = cpu.get_memory_map();
+                    for i in (map.start(Section::Program) as usize..map.end(Section::Program)).step_by(0x0100) {
+                        println!("{}", cpu.partial_mem_dump(i as u16))
                     }
                 }
                 "stack" => {
                     println!("[i] Stack memory dump:");
-                    for i in (cpu::STACK_START..cpu::BUILTIN_START).step_by(0x0100) {
-                        println!("{}", cpu.partial_mem_dump(i))
+                    let map = cpu.get_memory_map();
+                    for i in (map.start(Section::Stack) as usize..map.end(Section::Stack)).step_by(0x0100) {
+                        println!("{}", cpu.partial_mem_dump(i as u16))
                     }
                 }
+                "map" => println!("[i] Memory map:\n{}", cpu.get_memory_map()),
+                "seed" => println!("[i] RNG seed: {:#018x}, state: {:#018x}", cpu.get_rng().seed(), cpu.get_rng().state()),
+                "dev" => {
+                    println!("[i] Attached devices:");
+                    for (port, device) in cpu.get_devices() {
+                        println!("    {:#06x} {}", port, device.name());
+                    }
+                    for region in cpu.get_regions() {
+                        println!("    {:#06x}..{:#06x} {}", region.start, region.end(), region.device.name());
+                    }
+                }
+                "prot" => match cpu.get_protection() {
+                    Some(protection) => println!("[i] Memory protection:\n{}", protection),
+                    None => println!("[i] Memory protection is off"),
+                }
+                "prot on" => {
+                    cpu.set_protection(Some(ProtectionMap::default()));
+                    println!("[i] Memory protection enabled");
+                }
+                "prot off" => {
+                    cpu.set_protection(None);
+                    println!("[i] Memory protection disabled");
+                }
                 "mem" => {
                     println!("[i] Full memory dump:");
                     println!("{}", cpu.mem_dump());
                 }
                 "run" => {
                     run_delay = 0;
-                    run_thread = Some(thread::spawn(|| {
-                        let _ = io::stdin().read_line(&mut String::new());
-                    }));
+                    resume_fault(&mut cpu);
+                    running = Some(Running::start(keys_from_stdin));
                 }
                 _ => {
-                    if s.starts_with("run ") {
-                        match util::parse_u64(&s[4..]) {
+                    if let Some(arg) = s.strip_prefix("run ") {
+                        match util::parse_u64(arg) {
                             Ok(delay) => {
                                 run_delay = delay;
-                                run_thread = Some(thread::spawn(|| {
-                                    let _ = io::stdin().read_line(&mut String::new());
-                                }));
+                                resume_fault(&mut cpu);
+                                running = Some(Running::start(keys_from_stdin));
                             },
                             Err(err) => eprintln!("[!] Error parsing 'run' command:\n  {}", err)
                         }
-                    } else if s.starts_with("sec ") {
-                        match util::parse_u16(&s[4..]) {
+                    } else if let Some(arg) = s.strip_prefix("seed ") {
+                        match util::parse_u64(arg) {
+                            Ok(seed) => {
+                                cpu.set_seed(seed);
+                                println!("[i] RNG seeded with {:#018x}", seed);
+                            }
+                            Err(err) => eprintln!("[!] Error parsing 'seed' command:\n  {}", err)
+                        }
+                    } else if let Some(arg) = s.strip_prefix("sec ") {
+                        match util::parse_u16(arg) {
                             Ok(section) => {
                                 println!("[i] Section {:#06x} memory dump:", section);
                                 println!("{}", cpu.partial_mem_dump(section))
                             },
                             Err(err) => eprintln!("[!] Error parsing 'sec' command:\n  {}", err)
                         }
-                    } else if s.starts_with("get ") {
-                        let arg = &s[4..];
+                    } else if let Some(arg) = s.strip_prefix("get ") {
                         match arg {
                             "a" => eprintln!("[i] a = {}", cpu.get_a()),
                             "b" => eprintln!("[i] b = {}", cpu.get_b()),
@@ -348,8 +566,7 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
                                 Err(err) => eprintln!("[!] Error parsing 'get' command:\n  {}", err)
                             }
                         }
-                    } else if s.starts_with("set ") {
-                        let arg = &s[4..];
+                    } else if let Some(arg) = s.strip_prefix("set ") {
                         if let Some(second_space) = arg.find(' ') {
                             let first = &arg[..second_space];
                             let second = &arg[(second_space + 1)..];
@@ -366,6 +583,10 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
                                     Ok(value) => cpu.set_c(value),
                                     Err(err) => eprintln!("[!] Error parsing 'set' command:\n  {}", err)
                                 }
+                                "ictr" => match util::parse_u16(second) {
+                                    Ok(value) => cpu.set_instr_counter(value),
+                                    Err(err) => eprintln!("[!] Error parsing 'set' command:\n  {}", err)
+                                }
                                 first => match util::parse_u16(first) {
                                     Ok(address) => match util::parse_u16(&arg[(second_space + 1)..]) {
                                         Ok(value) => match cpu.set_address(address, value) {
@@ -381,9 +602,71 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
                             eprintln!("[!] Too few arguments, correct syntax: set <address|register> <value>")
                         }
                         if auto_info { println!("{}", cpu.registers_info()); }
-                    } else if s.starts_with("do ") {
-                        let text = &s[3..];
-                        match parse::compile(cpu.get_instr_counter(), text, path) {
+                    } else if let Some(arg) = s.strip_prefix("prot ") {
+                        match arg.split_once(' ') {
+                            Some((section, permissions)) => match (Section::from_name(section), Permissions::parse(permissions)) {
+                                (Some(section), Some(permissions)) => {
+                                    let mut protection = cpu.get_protection().cloned().unwrap_or_default();
+                                    protection.set(section, permissions);
+                                    cpu.set_protection(Some(protection));
+                                    println!("[i] Set permissions of section {} to {}", section, permissions);
+                                }
+                                (None, _) => eprintln!("[!] Unknown memory section '{}'", section),
+                                (_, None) => eprintln!("[!] Invalid permissions '{}', expected e.g. 'rw-'", permissions),
+                            }
+                            None => eprintln!("[!] Too few arguments, correct syntax: prot <section> <rwx>"),
+                        }
+                    } else if let Some(arg) = s.strip_prefix("img ") {
+                        match arg.rsplit_once(' ') {
+                            Some((image_path, address)) => match (fs::read(image_path), util::parse_u16(address)) {
+                                (Ok(bytes), Ok(_)) if bytes.len() & 0x1 > 0 => {
+                                    eprintln!("[!] Invalid compiled data in file {}: byte size {} is uneven!", image_path, bytes.len());
+                                }
+                                (Ok(bytes), Ok(address)) => match load_image(&mut cpu, &bytes, address) {
+                                    Ok(_) => println!("[i] Loaded {} words from {} at {:#06x}", bytes.len() >> 1, image_path, address),
+                                    Err(err) => eprintln!("[!] Error loading image {}:\n  {}", image_path, err),
+                                }
+                                (Err(err), _) => eprintln!("[!] Failed to read file {}:\n  {}", image_path, err),
+                                (_, Err(err)) => eprintln!("[!] Error parsing 'img' command:\n  {}", err),
+                            }
+                            None => eprintln!("[!] Too few arguments, correct syntax: img <path> <address>"),
+                        }
+                    } else if let Some(image_path) = s.strip_prefix("ppm ") {
+                        match fs::write(image_path, display::to_ppm(cpu.get_vram())) {
+                            Ok(_) => println!("[i] Wrote display to {}", image_path),
+                            Err(err) => eprintln!("[!] Failed to write file {}:\n  {}", image_path, err),
+                        }
+                    } else if let Some(state_path) = s.strip_prefix("save ") {
+                        match fs::write(state_path, cpu.save_state()) {
+                            Ok(_) => println!("[i] Saved state at cycle {} to {}", cpu.get_cycle(), state_path),
+                            Err(err) => eprintln!("[!] Failed to write file {}:\n  {}", state_path, err),
+                        }
+                    } else if let Some(state_path) = s.strip_prefix("load ") {
+                        match load_state(&mut cpu, state_path) {
+                            Ok(_) => {
+                                println!("[i] Loaded state at cycle {} from {}", cpu.get_cycle(), state_path);
+                                if auto_info { println!("{}", cpu.registers_info()); }
+                            }
+                            Err(err) => eprintln!("[!] Failed to load save state {}:\n  {}", state_path, err),
+                        }
+                    } else if let Some(arg) = s.strip_prefix("mmio ") {
+                        let args = arg.split(' ').map(util::parse_u16).collect::<Result<Vec<_>, _>>();
+                        match args.as_deref() {
+                            Ok(&[port, start, len]) => match cpu.check_region(start, len) {
+                                Ok(_) => match cpu.detach_device(port) {
+                                    Some(device) => {
+                                        println!("[i] Mapped {} to {:#06x}..{:#06x}", device.name(), start, start as usize + len as usize);
+                                        let _ = cpu.map_device(start, len, device);
+                                    }
+                                    None => eprintln!("[!] No device attached to port {:#06x}", port),
+                                }
+                                Err(err) => eprintln!("[!] Error mapping device on port {:#06x}:\n  {}", port, err),
+                            }
+                            Ok(_) => eprintln!("[!] Invalid arguments, correct syntax: mmio <port> <address> <length>"),
+                            Err(err) => eprintln!("[!] Error parsing 'mmio' command:\n  {}", err),
+                        }
+                    } else if let Some(text) = s.strip_prefix("do ") {
+                        match parse::compile(None, text, path, cpu.get_memory_map()) {
                             Ok(words) => {
                                 for word in words {
                                     match parse::parse(cpu.get_const_flag(), word) {
@@ -391,11 +674,12 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
                                             Ok(_) => {}
                                             Err(err) => {
                                                 eprintln!("[!] Error running instruction {}:\n  {}", text, err);
+                                                break;
                                             }
                                         }
                                         None => {
                                             eprintln!("[!] Error parsing instruction '{}', compiled into {:#06x}", text, word);
-                                            return;
+                                            break;
                                         }
                                     }
                                 }
@@ -413,3 +697,58 @@ pub fn run_emulator(mut cpu: Cpu, path: &str) {
         }
     }
 }
+
+fn load_image(cpu: &mut Cpu, bytes: &[u8], address: u16) -> Result<(), CpuError> {
+    for (i, word) in bytes.chunks_exact(2).enumerate() {
+        let target = address as usize + i;
+        if target >= cpu::CPU_MEMORY_SIZE {
+            return Err(CpuError::OutOfBounds { address: target, size: cpu::CPU_MEMORY_SIZE });
+        }
+        cpu.set_address(target as u16, ((word[0] as u16) << 8) | (word[1] as u16))?;
+    }
+    Ok(())
+}
+
+fn load_state(cpu: &mut Cpu, state_path: &str) -> Result<(), String> {
+    let bytes = fs::read(state_path).map_err(|err| err.to_string())?;
+    cpu.load_state(&bytes).map_err(|err| err.to_string())
+}
+
+struct Running {
+    _raw_mode: Option<terminal::RawMode>,
+}
+
+impl Running {
+    fn start(keys_from_stdin: bool) -> Running {
+        if keys_from_stdin {
+            println!("[i] Running, keys go to the keyboard, stop with Ctrl-C");
+            Running { _raw_mode: terminal::RawMode::enable() }
+        } else {
+            println!("[i] Running, stop with enter");
+            Running { _raw_mode: None }
+        }
+    }
+}
+
+fn record_frame(cpu: &Cpu, frames: &mut Option<FrameRecorder>) {
+    if let Some(recorder) = frames {
+        if let Err(err) = recorder.record(cpu.get_vram(), cpu.get_cycle()) {
+            eprintln!("[!] Failed to write frame, stopped recording:\n  {}", err);
+            *frames = None;
+        }
+    }
+}
+
+// running from a fault retries the instruction like 'cont', re-reading it in case it was patched
+fn resume_fault(cpu: &mut Cpu) {
+    if cpu.get_fault().is_some() {
+        cpu.resume();
+    }
+}
+
+fn print_fault(cpu: &Cpu) {
+    if let Some(fault) = cpu.get_fault() {
+        eprintln!("[!] CPU stopped:\n  {}", fault);
+        eprintln!("[i] Inspect with 'i', 'mem' or 'get', patch with 'set' and continue with 'cont'");
+    }
+}
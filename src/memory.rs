@@ -0,0 +1,239 @@
+use std::fmt;
+use crate::cpu::{BUILTIN_START, CPU_MEMORY_SIZE, HEAP_DATA_START, HEAP_META_START, IRQ_LINE_COUNT, PROGRAM_START, STACK_START, TRAP_VECTOR_COUNT, VRAM_START};
+use crate::util;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Section {
+    Vram,
+    Program,
+    Stack,
+    Builtin,
+    HeapMeta,
+    HeapData,
+}
+
+impl Section {
+    pub const ALL: [Section; 6] = [Section::Vram, Section::Program, Section::Stack, Section::Builtin, Section::HeapMeta, Section::HeapData];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Vram => "vram",
+            Section::Program => "program",
+            Section::Stack => "stack",
+            Section::Builtin => "builtin",
+            Section::HeapMeta => "heap_meta",
+            Section::HeapData => "heap_data",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Section> {
+        Section::ALL.into_iter().find(|section| section.name() == name)
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Section::Vram => 0,
+            Section::Program => 1,
+            Section::Stack => 2,
+            Section::Builtin => 3,
+            Section::HeapMeta => 4,
+            Section::HeapData => 5,
+        }
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+
+    // 0b100 is read, 0b010 write and 0b001 execute
+    pub fn bits(&self) -> u8 {
+        (self.read as u8) << 2 | (self.write as u8) << 1 | self.execute as u8
+    }
+
+    pub fn from_bits(bits: u8) -> Permissions {
+        Permissions { read: bits & 0b100 != 0, write: bits & 0b010 != 0, execute: bits & 0b001 != 0 }
+    }
+
+    // in the form rwx, using - for a missing permission
+    pub fn parse(s: &str) -> Option<Permissions> {
+        let s = s.as_bytes();
+        if s.len() != 3 {
+            return None;
+        }
+        let flag = |c: u8, set: u8| if c == set { Some(true) } else if c == b'-' { Some(false) } else { None };
+        Some(Permissions { read: flag(s[0], b'r')?, write: flag(s[1], b'w')?, execute: flag(s[2], b'x')? })
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { "r" } else { "-" },
+            if self.write { "w" } else { "-" },
+            if self.execute { "x" } else { "-" },
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectionMap {
+    permissions: [Permissions; 6],
+}
+
+impl ProtectionMap {
+    pub fn get(&self, section: Section) -> Permissions {
+        self.permissions[section.index()]
+    }
+
+    pub fn set(&mut self, section: Section, permissions: Permissions) {
+        self.permissions[section.index()] = permissions;
+    }
+
+    pub fn allows(&self, section: Section, access: Access) -> bool {
+        self.get(section).allows(access)
+    }
+}
+
+impl Default for ProtectionMap {
+    // the built-in section stays writable since it holds the trap and interrupt tables
+    fn default() -> Self {
+        let rw = Permissions { read: true, write: true, execute: false };
+        let rx = Permissions { read: true, write: false, execute: true };
+        let rwx = Permissions { read: true, write: true, execute: true };
+        ProtectionMap { permissions: [rw, rx, rw, rwx, rw, rw] }
+    }
+}
+
+impl fmt::Display for ProtectionMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, section) in Section::ALL.iter().enumerate() {
+            if i > 0 { writeln!(f)?; }
+            write!(f, "    {:10} {}", section.name(), self.get(*section))?;
+        }
+        Ok(())
+    }
+}
+
+// each section ends where the next one starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    starts: [u16; 6],
+}
+
+impl MemoryMap {
+    pub fn start(&self, section: Section) -> u16 {
+        self.starts[section.index()]
+    }
+
+    pub fn end(&self, section: Section) -> usize {
+        match Section::ALL.get(section.index() + 1) {
+            Some(next) => self.start(*next) as usize,
+            None => CPU_MEMORY_SIZE,
+        }
+    }
+
+    pub fn size(&self, section: Section) -> usize {
+        self.end(section) - self.start(section) as usize
+    }
+
+    pub fn section(&self, address: u16) -> Section {
+        Section::ALL.into_iter().rev().find(|section| address >= self.start(*section)).unwrap_or(Section::Vram)
+    }
+
+    pub fn trap_table(&self) -> u16 {
+        self.start(Section::HeapMeta) - TRAP_VECTOR_COUNT
+    }
+
+    pub fn irq_table(&self) -> u16 {
+        self.trap_table() - IRQ_LINE_COUNT
+    }
+
+    // sections that aren't listed keep their default start address
+    pub fn parse(s: &str) -> Result<MemoryMap, String> {
+        let mut map = MemoryMap::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() { continue; }
+            let (name, address) = line.split_once('=').ok_or_else(|| format!("line {}: expected '<section> = <address>'", i + 1))?;
+            let section = Section::from_name(name.trim()).ok_or_else(|| format!("line {}: unknown memory section '{}'", i + 1, name.trim()))?;
+            let address = util::parse_u16(address.trim()).map_err(|err| format!("line {}: {}", i + 1, err))?;
+            map.starts[section.index()] = address;
+        }
+        map.validate()?;
+        Ok(map)
+    }
+
+    // in the order of Section::ALL
+    pub fn from_starts(starts: [u16; 6]) -> Result<MemoryMap, String> {
+        let map = MemoryMap { starts };
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for pair in Section::ALL.windows(2) {
+            if self.start(pair[0]) >= self.start(pair[1]) {
+                return Err(format!("section {} must start before section {}", pair[0], pair[1]));
+            }
+        }
+        // the builtin section holds the trap and interrupt tables
+        if self.size(Section::Builtin) < (TRAP_VECTOR_COUNT + IRQ_LINE_COUNT) as usize {
+            return Err(format!("section {} is too small to hold the trap and interrupt tables", Section::Builtin));
+        }
+        Ok(())
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap { starts: [VRAM_START, PROGRAM_START, STACK_START, BUILTIN_START, HEAP_META_START, HEAP_DATA_START] }
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, section) in Section::ALL.iter().enumerate() {
+            if i > 0 { writeln!(f)?; }
+            write!(f, "    {:10} {:#06x}..{:#06x}", section.name(), self.start(*section), self.end(*section))?;
+        }
+        Ok(())
+    }
+}
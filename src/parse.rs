@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 use std::{fs, path};
 use path_absolutize::*;
-use crate::{cpu, SOURCE_FILE_EXTENSION};
-use crate::cpu::{AluInstr, CpuInstr, CpuConst, Register, ShiftAmount};
+use crate::{cpu, util, SOURCE_FILE_EXTENSION};
+use crate::error::{AsmError, AsmErrorKind};
+use crate::memory::MemoryMap;
+use crate::cpu::{AluInstr, CpuInstr, CpuConst, JumpCond, Register, ShiftAmount, Trap};
 
 pub const COMMENT_PREFIX: char = '#';
 pub const MACRO_PREFIX: char = '.';
@@ -18,46 +20,75 @@ fn trim_comment(code: &str) -> &str {
     code.trim()
 }
 
-pub fn expand_lines(code: &str, path: &str) -> Result<Vec<String>, String> {
-    let lines = code.to_ascii_lowercase();
-    let lines: Vec<&str> = lines.lines().map(|l| l.split(';')).flatten().map(|l| l.trim()).collect();
+#[derive(Clone, Debug)]
+pub struct SourcePos {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+}
 
-    let mut out = Vec::<String>::new();
-    for (i, line) in lines.iter().enumerate() {
-        if line.starts_with(MACRO_PREFIX) {
-            match expand_macro(trim_comment(line), &mut out, path) {
-                Ok(ok) => ok,
-                Err(err) => return Err(format!("Line {}:\n  {}", i + 1, err))
-            };
-        } else if !line.starts_with(COMMENT_PREFIX) && line.len() > 0 {
-            out.push(trim_comment(*line).into());
+impl SourcePos {
+    fn emit(&self, text: impl Into<String>) -> SourceLine {
+        SourceLine { text: text.into(), pos: self.clone() }
+    }
+
+    pub fn error(&self, kind: AsmErrorKind) -> AsmError {
+        AsmError { path: self.path.clone(), line: self.line, column: self.column, kind }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceLine {
+    pub text: String,
+    pub pos: SourcePos,
+}
+
+pub fn expand_lines(code: &str, path: &str) -> Result<Vec<SourceLine>, AsmError> {
+    let code = code.to_ascii_lowercase();
+
+    let mut out = Vec::<SourceLine>::new();
+    for (i, source_line) in code.lines().enumerate() {
+        let mut column = 1;
+        for part in source_line.split(';') {
+            let line = part.trim();
+            let pos = SourcePos { path: path.into(), line: i + 1, column: column + part.len() - part.trim_start().len() };
+            column += part.len() + 1;
+
+            if line.starts_with(MACRO_PREFIX) {
+                expand_macro(trim_comment(line), &mut out, &pos)?;
+            } else if !line.starts_with(COMMENT_PREFIX) && !line.is_empty() {
+                out.push(pos.emit(trim_comment(line)));
+            }
         }
     }
     Ok(out)
 }
 
-fn add_namespace_labels(code: &mut Vec<String>, namespace: &str) {
+fn add_namespace_labels(code: &mut [SourceLine], namespace: &str) {
     for line in code.iter_mut() {
-        if line.starts_with(LABEL_PREFIX) {
-            *line = format!("{}{}{}{}", LABEL_PREFIX, namespace, NAMESPACE_SEPARATOR, &line[1..]);
-        } else if line.starts_with(JUMP_PREFIX) {
+        let line = &mut line.text;
+        if let Some(label) = line.strip_prefix(LABEL_PREFIX) {
+            *line = format!("{}{}{}{}", LABEL_PREFIX, namespace, NAMESPACE_SEPARATOR, label);
+        } else if let Some(jump) = line.strip_prefix(JUMP_PREFIX) {
             let space = trim_comment(line).trim().find(' ');
             *line = if let Some(index) = space {
                 format!("{} {}{}{}", &line[..index], namespace, NAMESPACE_SEPARATOR, &line[(index + 1)..])
             } else {
-                format!("{}{}{}{}", JUMP_PREFIX, namespace, NAMESPACE_SEPARATOR, &line[1..])
+                format!("{}{}{}{}", JUMP_PREFIX, namespace, NAMESPACE_SEPARATOR, jump)
             }
         }
     }
 }
 
-fn expand_macro(line: &str, code: &mut Vec<String>, path: &str) -> Result<(), String> {
+fn expand_macro(line: &str, code: &mut Vec<SourceLine>, pos: &SourcePos) -> Result<(), AsmError> {
     let space = line.find(' ');
     let args = if let Some(index) = space { (line[(index + 1)..]).split(' ').collect::<Vec<&str>>() } else { vec![] };
-    match &line[1..(if let Some(p) = space { p } else { line.len() })] {
+    let name = &line[1..(if let Some(p) = space { p } else { line.len() })];
+    let arity = || pos.error(AsmErrorKind::MacroArity { name: name.into(), args: args.len() });
+    match name {
         "extern" => {
-            if args.len() != 1 { return Err(format!("Invalid number of arguments: {}", line)) }
-            let cwd = path::Path::new(path);
+            if args.len() != 1 { return Err(arity()) }
+            let cwd = path::Path::new(&pos.path);
             let tmp: String;
             let relative_path = if args[0].ends_with(SOURCE_FILE_EXTENSION) { args[0] } else { tmp = format!("{}{}", args[0], SOURCE_FILE_EXTENSION); &tmp[..] };
             let relative_path = path::Path::new(relative_path);
@@ -68,7 +99,7 @@ fn expand_macro(line: &str, code: &mut Vec<String>, path: &str) -> Result<(), St
                 let input = match fs::read_to_string(new_path) {
                     Ok(contents) => contents,
                     Err(err) => {
-                        return Err(format!("Failed to read file {}:\n  {}", new_path, err));
+                        return Err(pos.error(AsmErrorKind::ReadFile { path: new_path.into(), message: err.to_string() }));
                     }
                 };
                 let mut lines = expand_lines(input.as_str(), new_path)?;
@@ -77,236 +108,325 @@ fn expand_macro(line: &str, code: &mut Vec<String>, path: &str) -> Result<(), St
             }
         }
         "const" => {
-            if args.len() != 1 { return Err(format!("Invalid number of arguments: {}", line)) }
-            code.push("const".into());
-            code.push(format!("({})", args[0]));
+            if args.len() != 1 { return Err(arity()) }
+            code.push(pos.emit("const"));
+            code.push(pos.emit(format!("({})", args[0])));
         }
         "read" => {
-            if args.len() != 1 { return Err(format!("Invalid number of arguments: {}", line)) }
-            expand_macro(&format!(".const {}", args[0]), code, path)?;
-            code.push("movab".into());
-            code.push("memr".into());
+            if args.len() != 1 { return Err(arity()) }
+            expand_macro(&format!(".const {}", args[0]), code, pos)?;
+            code.push(pos.emit("movab"));
+            code.push(pos.emit("memr"));
         }
         "write" => {
-            if args.len() != 1 { return Err(format!("Invalid number of arguments: {}", line)) }
-            code.push("movab".into());
-            expand_macro(&format!(".const {}", args[0]), code, path)?;
-            code.push("memw".into());
+            if args.len() != 1 { return Err(arity()) }
+            code.push(pos.emit("movab"));
+            expand_macro(&format!(".const {}", args[0]), code, pos)?;
+            code.push(pos.emit("memw"));
         }
         "err" => {
-            if args.len() > 1 { return Err(format!("Invalid number of arguments: {}", line)) }
+            if args.len() > 1 { return Err(arity()) }
             let error_code = if args.len() == 1 { args[0] } else { "0xffff" };
-            expand_macro(&format!(".const {}", error_code), code, path)?;
-            code.push("seterr".into());
-            code.push("pause".into());
+            expand_macro(&format!(".const {}", error_code), code, pos)?;
+            code.push(pos.emit("seterr"));
+            code.push(pos.emit("pause"));
+        }
+        "trap" => {
+            if args.len() != 2 { return Err(arity()) }
+            let vector = match Trap::from_name(args[0]) {
+                Some(trap) => trap.vector(),
+                None => match util::parse_u16(args[0]) {
+                    Ok(vector) if vector < cpu::TRAP_VECTOR_COUNT => vector,
+                    _ => return Err(pos.error(AsmErrorKind::InvalidConstant(args[0].into()))),
+                },
+            };
+            code.push(pos.emit(format!("%a {}", args[1])));
+            code.push(pos.emit("movab"));
+            expand_macro(".const trap_table", code, pos)?;
+            code.push(pos.emit(format!("addi {}", vector)));
+            code.push(pos.emit("memw"));
+        }
+        "irq" => {
+            if args.len() != 2 { return Err(arity()) }
+            let line = match args[0] {
+                "timer" => cpu::TIMER_IRQ_LINE,
+                "keyboard" => cpu::KEYBOARD_IRQ_LINE,
+                arg => match util::parse_u16(arg) {
+                    Ok(line) if line < cpu::IRQ_LINE_COUNT => line,
+                    _ => return Err(pos.error(AsmErrorKind::InvalidConstant(arg.into()))),
+                },
+            };
+            code.push(pos.emit(format!("%a {}", args[1])));
+            code.push(pos.emit("movab"));
+            expand_macro(".const irq_table", code, pos)?;
+            code.push(pos.emit(format!("addi {}", line)));
+            code.push(pos.emit("memw"));
         }
         "push" => {
-            if args.len() > 1 { return Err(format!("Invalid number of arguments: {}", line)) }
+            if args.len() > 1 { return Err(arity()) }
             if args.len() == 1 {
-                expand_macro(&format!(".const {}", args[0]), code, path)?;
-                code.push("movab".into());
-                code.push("sctr".into());
-                code.push("memw".into());
-                code.push("inc".into());
-                code.push("msctr".into());
-            } else {
-                code.push("movab".into());
-                code.push("sctr".into());
-                code.push("memw".into());
-                code.push("inc".into());
-                code.push("msctr".into());
+                expand_macro(&format!(".const {}", args[0]), code, pos)?;
             }
+            code.push(pos.emit("push"));
         }
         "pop" => {
-            if args.len() == 0  {
-                code.push("sctr".into());
-                code.push("dec".into());
-                code.push("msctr".into());
-                code.push("movab".into());
-                code.push("memr".into());
+            if args.is_empty() {
+                code.push(pos.emit("pop"));
             } else if args.len() == 1 {
-                expand_macro(&format!(".const {}", args[0]), code, path)?;
-                code.push("movab".into());
-                code.push("sctr".into());
-                code.push("sub".into());
-                code.push("msctr".into());
+                code.push(pos.emit(format!("drop {}", args[0])));
             } else {
-                return Err(format!("Invalid number of arguments: {}", line))
+                return Err(arity())
             }
         }
         "popn" => {
-            if args.len() != 0 { return Err(format!("Invalid number of arguments: {}", line)) }
-            code.push("sctr".into());
-            code.push("dec".into());
-            code.push("msctr".into());
+            if !args.is_empty() { return Err(arity()) }
+            code.push(pos.emit("drop 1"));
         }
         "peek" => {
-            if args.len() == 0  {
-                code.push("sctr".into());
-                code.push("dec".into());
-                code.push("movab".into());
-                code.push("memr".into());
+            if args.is_empty() {
+                code.push(pos.emit("peek 0"));
             } else if args.len() == 1 {
-                expand_macro(&format!(".const {}", args[0]), code, path)?;
-                code.push("movab".into());
-                code.push("sctr".into());
-                code.push("dec".into());
-                code.push("sub".into());
-                code.push("movab".into());
-                code.push("memr".into());
+                code.push(pos.emit(format!("peek {}", args[0])));
             } else {
-                return Err(format!("Invalid number of arguments: {}", line))
+                return Err(arity())
             }
         }
         "rep" => {
-            if args.len() == 0 {
-                code.push("sctr".into());
-                code.push("dec".into());
-                code.push("memw".into());
+            if args.is_empty() {
+                code.push(pos.emit("rep 0"));
             } else if args.len() == 1 {
-                expand_macro(&format!(".const {}", args[0]), code, path)?;
-                code.push("movab".into());
-                code.push("sctr".into());
-                code.push("sub".into());
-                code.push("dec".into());
-                code.push("movcb".into());
-                code.push("memw".into());
+                code.push(pos.emit(format!("rep {}", args[0])));
             } else {
-                return Err(format!("Invalid number of arguments: {}", line));
+                return Err(arity());
             }
         }
         "stackstat" => {
-            code.push("sctr".into());
-            code.push("movab".into());
-            expand_macro(".const BUILTIN", code, path)?;
-            code.push("sub".into());
+            code.push(pos.emit("sctr"));
+            code.push(pos.emit("movab"));
+            expand_macro(".const BUILTIN", code, pos)?;
+            code.push(pos.emit("sub"));
         }
         "call" => {
-            if args.len() != 1 { return Err(format!("Invalid number of arguments: {}", line)) }
-            code.push("ictr".into());
-            code.push("movab".into());
-            expand_macro(".const 13", code, path)?;
-            code.push("add".into());
-            expand_macro(".push", code, path)?;
-            code.push(format!("%{}", args[0]));
+            if args.len() != 1 { return Err(arity()) }
+            code.push(pos.emit(format!("%call {}", args[0])));
         }
         "return" => {
-            if args.len() == 0 {
-                expand_macro(".peek", code, path)?;
-                code.push("jmp".into());
+            if args.is_empty() {
+                code.push(pos.emit("ret"));
             } else if args.len() == 1 {
-                expand_macro(&format!(".peek {}", args[0]), code, path)?;
-                code.push("jmp".into());
+                expand_macro(&format!(".const {}", args[0]), code, pos)?;
+                code.push(pos.emit("movab"));
+                code.push(pos.emit("retn"));
             } else {
-                return Err(format!("Invalid number of arguments: {}", line));
+                return Err(arity());
             }
         }
         "str" => {
             let s = args.join(" ");
-            expand_macro(&format!(".const {}", s.len()), code, path)?;
-            code.push("inc".into());
-            expand_macro(".push", code, path)?;
-            expand_macro(".call std/alloc", code, path)?;
-            expand_macro(".pop", code, path)?;
-            code.push("movac".into());
-            expand_macro(".popn", code, path)?;
-            expand_macro(".pop", code, path)?;
-            code.push("dec".into());
-            code.push("movab".into());
-            code.push("movca".into());
-            code.push("memw".into());
+            expand_macro(&format!(".const {}", s.len()), code, pos)?;
+            code.push(pos.emit("inc"));
+            expand_macro(".push", code, pos)?;
+            expand_macro(".call std/alloc", code, pos)?;
+            expand_macro(".pop", code, pos)?;
+            code.push(pos.emit("movac"));
+            expand_macro(".pop", code, pos)?;
+            code.push(pos.emit("dec"));
+            code.push(pos.emit("movab"));
+            code.push(pos.emit("movca"));
+            code.push(pos.emit("memw"));
             for c in s.chars() {
-                code.push("inc".into());
-                code.push("movac".into());
-                expand_macro(&format!(".const {}", c as u8), code, path)?;
-                code.push("movab".into());
-                code.push("movca".into());
-                code.push("memw".into());
+                code.push(pos.emit("inc"));
+                code.push(pos.emit("movac"));
+                expand_macro(&format!(".const {}", c as u8), code, pos)?;
+                code.push(pos.emit("movab"));
+                code.push(pos.emit("movca"));
+                code.push(pos.emit("memw"));
             }
-            if s.len() > 0 {
-                code.push("movca".into());
+            if !s.is_empty() {
+                code.push(pos.emit("movca"));
+            }
+        }
+        "pstr" => {
+            // same layout as '.str', but with two characters per word and the high byte first
+            let s = args.join(" ");
+            let words = s.as_bytes().chunks(2).map(|pair| (pair[0] as u16) << 8 | pair.get(1).copied().unwrap_or(0) as u16).collect::<Vec<_>>();
+            expand_macro(&format!(".const {}", words.len()), code, pos)?;
+            code.push(pos.emit("inc"));
+            expand_macro(".push", code, pos)?;
+            expand_macro(".call std/alloc", code, pos)?;
+            expand_macro(".pop", code, pos)?;
+            code.push(pos.emit("movac"));
+            expand_macro(".popn", code, pos)?;
+            expand_macro(&format!(".const {}", s.len()), code, pos)?;
+            code.push(pos.emit("movab"));
+            code.push(pos.emit("movca"));
+            code.push(pos.emit("memw"));
+            for word in words {
+                code.push(pos.emit("inc"));
+                code.push(pos.emit("movac"));
+                expand_macro(&format!(".const {}", word), code, pos)?;
+                code.push(pos.emit("movab"));
+                code.push(pos.emit("movca"));
+                code.push(pos.emit("memw"));
+            }
+            if !s.is_empty() {
+                code.push(pos.emit("movca"));
             }
         }
         "print" => {
             let s = args.join(" ").replace("\\n", "\n");
             for c in s.chars() {
                 if c.is_ascii() {
-                    expand_macro(&format!(".const {}", c as u8), code, path)?;
-                    code.push("movab".into());
-                    expand_macro(".const 0x0000", code, path)?;
-                    code.push("outp".into());
+                    expand_macro(&format!(".const {}", c as u8), code, pos)?;
+                    code.push(pos.emit("movab"));
+                    expand_macro(".const 0x0000", code, pos)?;
+                    code.push(pos.emit("outp"));
                 }
             }
         }
         m => {
-            return Err(format!("Invalid macro '{}'", m));
+            return Err(pos.error(AsmErrorKind::InvalidMacro(m.into())));
         },
     }
     Ok(())
 }
 
-fn compile_jumps(address_start: u16, lines: &Vec<String>) -> Result<Vec<String>, String> {
-    let mut offset = 0i64;
+fn split_jump(line: &str) -> (&str, &str) {
+    match line.find(' ') {
+        Some(space) => (&line[..space], &line[(space + 1)..]),
+        None => ("", line),
+    }
+}
 
-    let mut labels = HashMap::<String, u16>::new();
-    for (i, line) in lines.iter().enumerate() {
-        if line.starts_with(JUMP_PREFIX) {
-            offset += 2;
-        } else if line.starts_with(LABEL_PREFIX) {
-            let l = &line[1..];
-            let l = l.replace(' ', "");
-            if l.len() == 0 { return Err(format!("Invalid label '{}'", line)); }
-            labels.insert(l, (i as i64 + offset) as u16);
-            offset -= 1;
+fn fold_consts(lines: Vec<SourceLine>, map: &MemoryMap) -> Vec<SourceLine> {
+    let mut out = Vec::<SourceLine>::new();
+    let mut lines = lines.into_iter().peekable();
+    while let Some(line) = lines.next() {
+        if line.text == "const" {
+            if let Some(Ok(CpuInstr::Const(val))) = lines.peek().map(|next| CpuInstr::get_instr(next.text.trim(), map)) {
+                if val <= 0xff {
+                    lines.next();
+                    out.push(line.pos.emit(format!("seti {}", val)));
+                    continue;
+                }
+            }
         }
+        out.push(line);
     }
+    out
+}
 
-    let mut out = Vec::<String>::new();
-    for line in lines.iter() {
-        if line.starts_with(JUMP_PREFIX) {
-            let l = &line[1..];
+// address_start of None resolves labels to relative offsets
+fn compile_jumps(address_start: Option<u16>, lines: &[SourceLine]) -> Result<Vec<SourceLine>, AsmError> {
+    // every jump starts out in its long form, jumps whose target ends up in range of a short relative jump are
+    // shrunk until no more jumps can be, which only ever moves the remaining targets closer
+    let mut short = vec![false; lines.len()];
+    let (addresses, labels) = loop {
+        let mut addresses = Vec::<u16>::with_capacity(lines.len());
+        let mut labels = HashMap::<String, u16>::new();
+        let mut address = 0u16;
+        for (i, line) in lines.iter().enumerate() {
+            addresses.push(address);
+            if let Some(l) = line.text.strip_prefix(JUMP_PREFIX) {
+                // an absolute '%a' only loads the address, every other jump also emits an instruction
+                address += match split_jump(l).0 {
+                    _ if short[i] => 1,
+                    "a" if address_start.is_some() => 2,
+                    _ => 3,
+                };
+            } else if let Some(l) = line.text.strip_prefix(LABEL_PREFIX) {
+                let l = l.replace(' ', "");
+                if l.is_empty() { return Err(line.pos.error(AsmErrorKind::InvalidLabel(line.text.clone()))); }
+                labels.insert(l, address);
+            } else {
+                address += 1;
+            }
+        }
 
-            let (jump, label) = match l.find(' ') {
-                Some(space) => (&l[..space], &l[(space + 1)..]),
-                None => ("", l),
-            };
+        let mut changed = false;
+        for (i, line) in lines.iter().enumerate() {
+            let Some(l) = line.text.strip_prefix(JUMP_PREFIX) else { continue };
+            let (jump, label) = split_jump(l);
+            if short[i] || jump == "a" || jump == "call" { continue }
+            if let Some(target) = labels.get(&label.replace(' ', "")) {
+                if (-0x80..=0x7f).contains(&(*target as i32 - addresses[i] as i32)) {
+                    short[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !changed { break (addresses, labels) }
+    };
+
+    let mut out = Vec::<SourceLine>::new();
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(l) = line.text.strip_prefix(JUMP_PREFIX) {
+            let (jump, label) = split_jump(l);
 
-            let instr = match jump {
-                "" => "jmp",
-                "n" => "jmpn",
-                "z" => "jmpz",
-                "nz" => "jmpnz",
-                "o" => "jmpo",
-                val => return Err(format!("Invalid jump instruction: '{}'", val))
+            let cond = match jump {
+                "a" | "call" => None,
+                val => match JumpCond::from_suffix(val) {
+                    Some(cond) => Some(cond),
+                    None => return Err(line.pos.error(AsmErrorKind::InvalidJump(val.into()))),
+                }
             };
 
             let label = label.replace(' ', "");
-            let address = match labels.get(&label) {
-                Some(val) => address_start + *val,
-                None => return Err(format!("Label '{}' doesn't exist", label)),
+            let target = match labels.get(&label) {
+                Some(val) => *val,
+                None => return Err(line.pos.error(AsmErrorKind::UnknownLabel(label))),
             };
 
-            out.push("const".into());
-            out.push(format!("({})", address));
-            out.push(instr.into());
-        } else if !line.starts_with(LABEL_PREFIX) {
-            out.push(line.into());
+            if short[i] {
+                let cond = cond.unwrap_or(JumpCond::Always);
+                out.push(line.pos.emit(format!("jr{} {}", cond.suffix(), target as i32 - addresses[i] as i32)));
+                continue;
+            }
+            out.push(line.pos.emit("const"));
+            let instr = match address_start {
+                Some(address_start) => {
+                    out.push(line.pos.emit(format!("({})", address_start.wrapping_add(target))));
+                    match cond {
+                        Some(cond) => Some(format!("jmp{}", cond.suffix())),
+                        None if jump == "call" => Some("call".into()),
+                        None => None,
+                    }
+                }
+                None => {
+                    // offsets are relative to the instruction following the constant
+                    out.push(line.pos.emit(format!("({})", target.wrapping_sub(addresses[i] + 2))));
+                    match cond {
+                        Some(cond) => Some(format!("jmpr{}", cond.suffix())),
+                        None if jump == "call" => Some("callr".into()),
+                        None => Some("addpc".into()),
+                    }
+                }
+            };
+            if let Some(instr) = instr {
+                out.push(line.pos.emit(instr));
+            }
+        } else if !line.text.starts_with(LABEL_PREFIX) {
+            out.push(line.clone());
         }
     }
     Ok(out)
 }
 
-pub fn compile(address_start: u16, code: &str, path: &str) -> Result<Vec<u16>, String> {
+// address_start of None compiles position-independent code
+pub fn compile(address_start: Option<u16>, code: &str, path: &str, map: &MemoryMap) -> Result<Vec<u16>, AsmError> {
     // MACROS
     let lines = expand_lines(code, path)?;
 
+    // SHORT FORMS
+    let lines = fold_consts(lines, map);
+
     // LABELS
     let lines = compile_jumps(address_start, &lines)?;
 
     // COMPILE
     let mut v = vec![0u16; lines.len()];
     for (i, line) in lines.into_iter().enumerate() {
-        v[i] = cpu::CpuInstr::get_instr(i, line.trim())?.instr_code();
+        v[i] = cpu::CpuInstr::get_instr(line.text.trim(), map).map_err(|kind| line.pos.error(kind))?.instr_code();
     }
     Ok(v)
 }
@@ -345,6 +465,11 @@ pub fn parse(const_flag: bool, val :u16) -> Option<CpuInstr> {
         0x0032 => CpuInstr::JumpIfNeg,
         0x0033 => CpuInstr::JumpIfNegOrZero,
         0x0034 => CpuInstr::JumpIfOverflow,
+        0x0035 => CpuInstr::JumpIfCarry,
+        0x0036 => CpuInstr::JumpIfLess,
+        0x0037 => CpuInstr::JumpIfGreaterOrEqual,
+        0x0038 => CpuInstr::JumpIfLessOrEqual,
+        0x0039 => CpuInstr::JumpIfGreater,
         0x0040 | 0x0041 => CpuInstr::AluInstr { instr: AluInstr::NoOp, pass: true },
         0x0042 => CpuInstr::AluInstr { instr: AluInstr::Increment, pass: true },
         0x0043 => CpuInstr::AluInstr { instr: AluInstr::Increment, pass: false },
@@ -369,6 +494,14 @@ pub fn parse(const_flag: bool, val :u16) -> Option<CpuInstr> {
         0x0057 => CpuInstr::AluInstr { instr: AluInstr::ShiftLeftVar, pass: false },
         0x0058 => CpuInstr::AluInstr { instr: AluInstr::ShiftRightVar, pass: true },
         0x0059 => CpuInstr::AluInstr { instr: AluInstr::ShiftRightVar, pass: false },
+        0x005a => CpuInstr::AluInstr { instr: AluInstr::Divide, pass: true },
+        0x005b => CpuInstr::AluInstr { instr: AluInstr::Divide, pass: false },
+        0x005c => CpuInstr::AluInstr { instr: AluInstr::Modulo, pass: true },
+        0x005d => CpuInstr::AluInstr { instr: AluInstr::Modulo, pass: false },
+        0x005e => CpuInstr::AluInstr { instr: AluInstr::AddWithCarry, pass: true },
+        0x005f => CpuInstr::AluInstr { instr: AluInstr::AddWithCarry, pass: false },
+        0x0060 => CpuInstr::AluInstr { instr: AluInstr::SubtractWithBorrow, pass: true },
+        0x0061 => CpuInstr::AluInstr { instr: AluInstr::SubtractWithBorrow, pass: false },
         0x0062 => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S1), pass: true },
         0x0063 => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S1), pass: false },
         0x0064 => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S2), pass: true },
@@ -399,6 +532,8 @@ pub fn parse(const_flag: bool, val :u16) -> Option<CpuInstr> {
         0x007d => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S14), pass: false },
         0x007e => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S15), pass: true },
         0x007f => CpuInstr::AluInstr { instr: AluInstr::ShiftLeft(ShiftAmount::S15), pass: false },
+        0x0080 => CpuInstr::AluInstr { instr: AluInstr::ShiftRightArith, pass: true },
+        0x0081 => CpuInstr::AluInstr { instr: AluInstr::ShiftRightArith, pass: false },
         0x0082 => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S1), pass: true },
         0x0083 => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S1), pass: false },
         0x0084 => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S2), pass: true },
@@ -429,6 +564,60 @@ pub fn parse(const_flag: bool, val :u16) -> Option<CpuInstr> {
         0x009d => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S14), pass: false },
         0x009e => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S15), pass: true },
         0x009f => CpuInstr::AluInstr { instr: AluInstr::ShiftRight(ShiftAmount::S15), pass: false },
-        _ => return None,
+        0x00a0 => CpuInstr::GetError,
+        0x00a1 => CpuInstr::MoveToTrapTable,
+        0x00a2 => CpuInstr::TrapCounter,
+        0x00a3 => CpuInstr::ReturnFromTrap,
+        0x00a4 => CpuInstr::MoveToTrapCounter,
+        0x00a8 => CpuInstr::EnableInterrupts,
+        0x00a9 => CpuInstr::DisableInterrupts,
+        0x00aa => CpuInstr::ReturnFromInterrupt,
+        0x00ab => CpuInstr::WaitForInterrupt,
+        0x00ac => CpuInstr::MoveToInterruptMask,
+        0x00ad => CpuInstr::MoveToInterruptTable,
+        0x00b0 => CpuInstr::MoveToStackBase,
+        0x00b1 => CpuInstr::MoveToStackLimit,
+        0x00b8 => CpuInstr::WrapMode,
+        0x00b9 => CpuInstr::SaturateMode,
+        0x00ba => CpuInstr::HighWord,
+        0x00bc => CpuInstr::MemCopy,
+        0x00bd => CpuInstr::MemSet,
+        0x00c0 | 0x00c1 => CpuInstr::AluInstr { instr: AluInstr::SignedCompare, pass: true },
+        0x00c2 => CpuInstr::AluInstr { instr: AluInstr::SignedMultiply, pass: true },
+        0x00c3 => CpuInstr::AluInstr { instr: AluInstr::SignedMultiply, pass: false },
+        0x00c4..=0x00cd => CpuInstr::JumpRelative(JumpCond::ALL[(val - 0x00c4) as usize]),
+        0x00ce => CpuInstr::CallRelative,
+        0x00cf => CpuInstr::AddInstrCounter,
+        0x00d0 => CpuInstr::Call,
+        0x00d1 => CpuInstr::Return,
+        0x00d2 => CpuInstr::ReturnFrom,
+        0x00d4 => CpuInstr::Push,
+        0x00d5 => CpuInstr::Pop,
+        0x00da => CpuInstr::MemReadByte,
+        0x00db => CpuInstr::MemWriteByte,
+        0x00dc => CpuInstr::MemReadIndexed,
+        0x00dd => CpuInstr::MemWriteIndexed,
+        0x00de => CpuInstr::MemReadStack,
+        0x00df => CpuInstr::MemWriteStack,
+        _ => match val & 0x00ff {
+            0x00d6 => CpuInstr::Peek((val >> 8) as u8),
+            0x00d7 => CpuInstr::Replace((val >> 8) as u8),
+            0x00d8 => CpuInstr::Drop((val >> 8) as u8),
+            0x00e0 => CpuInstr::SetImmediate((val >> 8) as u8),
+            code @ 0x00e2..=0x00eb => {
+                let instr = match code & 0x00fe {
+                    0x00e2 => AluInstr::Add,
+                    0x00e4 => AluInstr::Subtract,
+                    0x00e6 => AluInstr::And,
+                    0x00e8 => AluInstr::Or,
+                    _ => AluInstr::Xor,
+                };
+                CpuInstr::AluImmediate { instr, pass: code & 1 == 0, val: (val >> 8) as u8 }
+            }
+            code @ 0x00f0..=0x00f9 => {
+                CpuInstr::JumpShort { cond: JumpCond::ALL[(code - 0x00f0) as usize], offset: (val >> 8) as u8 as i8 }
+            }
+            _ => return None,
+        },
     })
 }
\ No newline at end of file
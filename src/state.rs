@@ -0,0 +1,111 @@
+use crate::error::CpuError;
+
+pub const STATE_MAGIC: &[u8; 8] = b"EMBERSAV";
+// bumped whenever the layout of a save state changes, other versions aren't loaded
+pub const STATE_VERSION: u16 = 2;
+
+// all values are big-endian
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.raw(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.raw(&value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.raw(&value.to_be_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    // prefixed with a u32 length
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.raw(bytes);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        StateReader { bytes, position: 0 }
+    }
+
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8], CpuError> {
+        if self.bytes.len() - self.position < len {
+            return Err(CpuError::InvalidState(format!("unexpected end of data at byte {}", self.position)));
+        }
+        self.position += len;
+        Ok(&self.bytes[(self.position - len)..self.position])
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CpuError> {
+        Ok(self.raw(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, CpuError> {
+        Ok(u16::from_be_bytes(self.raw(2)?.try_into().unwrap_or_default()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CpuError> {
+        Ok(u32::from_be_bytes(self.raw(4)?.try_into().unwrap_or_default()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CpuError> {
+        Ok(u64::from_be_bytes(self.raw(8)?.try_into().unwrap_or_default()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CpuError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(CpuError::InvalidState(format!("invalid flag value {} at byte {}", value, self.position - 1))),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], CpuError> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, CpuError> {
+        std::str::from_utf8(self.bytes()?).map_err(|err| CpuError::InvalidState(err.to_string()))
+    }
+
+    pub fn finish(&self) -> Result<(), CpuError> {
+        if self.position != self.bytes.len() {
+            return Err(CpuError::InvalidState(format!("{} unexpected bytes at the end", self.bytes.len() - self.position)));
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,16 @@
-use std::u16;
-use std::u64;
-
 pub fn parse_u16(s: &str) -> Result<u16, String> {
-    if s.starts_with("0x") {
-        match u16::from_str_radix(&s[2..], 16) {
+    if let Some(hex) = s.strip_prefix("0x") {
+        match u16::from_str_radix(hex, 16) {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
-    } else if s.starts_with("0b") {
-        match u16::from_str_radix(&s[2..], 2) {
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        match u16::from_str_radix(bin, 2) {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
     } else {
-        match u16::from_str_radix(s, 10) {
+        match s.parse::<u16>() {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
@@ -21,20 +18,20 @@ pub fn parse_u16(s: &str) -> Result<u16, String> {
 }
 
 pub fn parse_u64(s: &str) -> Result<u64, String> {
-    if s.starts_with("0x") {
-        match u64::from_str_radix(&s[2..], 16) {
+    if let Some(hex) = s.strip_prefix("0x") {
+        match u64::from_str_radix(hex, 16) {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
-    } else if s.starts_with("0b") {
-        match u64::from_str_radix(&s[2..], 2) {
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        match u64::from_str_radix(bin, 2) {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
     } else {
-        match u64::from_str_radix(s, 10) {
+        match s.parse::<u64>() {
             Ok(val) => Ok(val),
             Err(err) => Err(err.to_string())
         }
     }
-}
\ No newline at end of file
+}